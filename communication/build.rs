@@ -7,6 +7,8 @@ fn main() {
     tonic_build::configure()
         .build_server(true)
         .type_attribute(".", "#[derive(serde::Deserialize, serde::Serialize)]")
+        // So json datums from before kinds existed still parse.
+        .field_attribute("goodmetrics.Measurement.kind", "#[serde(default)]")
         .file_descriptor_set_path(out_dir.join("goodmetrics_descriptor.bin"))
         .compile(&["../proto/metrics/goodmetrics.proto"], &["../proto"])
         .unwrap();
@@ -14,6 +16,7 @@ fn main() {
     tonic_build::configure()
         .build_server(false)
         // .type_attribute(".", "#[derive(Debug)]")
+        .protoc_arg("--experimental_allow_proto3_optional")
         .compile(
             &[
                 "../proto/opentelemetry/metrics/v1/metrics.proto",
@@ -22,4 +25,10 @@ fn main() {
             &["../proto/opentelemetry"],
         )
         .unwrap();
+
+    tonic_build::configure()
+        .build_server(false)
+        .build_client(false)
+        .compile(&["../proto/prometheus/remote.proto"], &["../proto"])
+        .unwrap();
 }
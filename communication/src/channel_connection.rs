@@ -1,21 +1,52 @@
 use std::{str::FromStr, sync::Arc};
 
 use hyper::{client::HttpConnector, http, Body, Error, Request, Response, Uri};
-use tokio_rustls::rustls::{client::ServerCertVerifier, ClientConfig, RootCertStore};
+use tokio_rustls::rustls::{
+    client::ServerCertVerifier, Certificate, ClientConfig, PrivateKey, RootCertStore,
+};
 use tonic::body::BoxBody;
 use tower::{buffer::Buffer, util::BoxService, ServiceExt};
 
 pub type ChannelType =
     Buffer<BoxService<Request<BoxBody>, Response<Body>, Error>, Request<BoxBody>>;
 
+/// A pem certificate chain and private key, for servers that want mutual tls.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    pub certificate_pem: Vec<u8>,
+    pub private_key_pem: Vec<u8>,
+}
+
 pub async fn get_channel(
     endpoint: &str,
     insecure: bool,
 ) -> Result<ChannelType, Box<dyn std::error::Error>> {
-    let mut tls = ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(RootCertStore::empty())
-        .with_no_client_auth();
+    get_channel_with_client_certificate(endpoint, insecure, None).await
+}
+
+pub async fn get_channel_with_client_certificate(
+    endpoint: &str,
+    insecure: bool,
+    client_certificate: Option<ClientCertificate>,
+) -> Result<ChannelType, Box<dyn std::error::Error>> {
+    let tls = ClientConfig::builder().with_safe_defaults();
+    let mut tls = match client_certificate {
+        Some(client_certificate) => {
+            // Mutual tls is pointless without checking the server too, so use the platform's roots.
+            let mut roots = RootCertStore::empty();
+            let native_certificates: Vec<Vec<u8>> = rustls_native_certs::load_native_certs()?
+                .into_iter()
+                .map(|certificate| certificate.0)
+                .collect();
+            roots.add_parsable_certificates(&native_certificates);
+            let (chain, key) = parse_client_certificate(&client_certificate)?;
+            tls.with_root_certificates(roots)
+                .with_client_auth_cert(chain, key)?
+        }
+        None => tls
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth(),
+    };
     if insecure {
         tls.dangerous()
             .set_certificate_verifier(Arc::new(StupidVerifier {}));
@@ -68,6 +99,27 @@ pub async fn get_channel(
     Ok(Buffer::new(service, 1024))
 }
 
+pub fn parse_client_certificate(
+    client_certificate: &ClientCertificate,
+) -> Result<(Vec<Certificate>, PrivateKey), Box<dyn std::error::Error>> {
+    let chain: Vec<Certificate> =
+        rustls_pemfile::certs(&mut client_certificate.certificate_pem.as_slice())?
+            .into_iter()
+            .map(Certificate)
+            .collect();
+    let key = rustls_pemfile::read_all(&mut client_certificate.private_key_pem.as_slice())?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or("client private key pem has no private key")?;
+
+    Ok((chain, key))
+}
+
 struct StupidVerifier {}
 
 impl ServerCertVerifier for StupidVerifier {
@@ -1,7 +1,10 @@
 mod channel_connection;
 
 pub use channel_connection::get_channel;
+pub use channel_connection::get_channel_with_client_certificate;
+pub use channel_connection::parse_client_certificate;
 pub use channel_connection::ChannelType;
+pub use channel_connection::ClientCertificate;
 
 #[allow(clippy::unwrap_used)]
 pub mod proto {
@@ -34,4 +37,8 @@ pub mod proto {
             }
         }
     }
+
+    pub mod prometheus {
+        tonic::include_proto!("prometheus");
+    }
 }
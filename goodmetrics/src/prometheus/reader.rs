@@ -208,6 +208,7 @@ fn read_a_thing(measurement_name: &str, line: &str, unix_nanos: u64) -> Option<D
         "value".to_string(),
         Measurement {
             value: Some(measurement::Value::F64(number)),
+            ..Default::default()
         },
     );
 
@@ -318,6 +319,7 @@ fn read_histogram(
                     value: Some(measurement::Value::Histogram(Histogram {
                         buckets: HashMap::new(),
                     })),
+                    ..Default::default()
                 },
             )]),
             ..Default::default()
@@ -3,6 +3,23 @@ use std::time::Duration;
 use clap::Parser;
 use serde_derive::Deserialize;
 
+use crate::{
+    ingest::{
+        sampling::{parse_sample_rule, SampleRule},
+        tenant::{parse_api_key_tenant, ApiKeyTenant},
+        timestamp_policy::TimestampAction,
+    },
+    postgres_things::{
+        postgres_connector::PostgresSslMode,
+        table_policy::{parse_table_policy_rule, TablePolicyRule},
+    },
+    sink::{
+        debug_sink::DebugFormat,
+        opentelemetry_sink::{parse_kind_rule, parse_unit_rule, KindRule, OtlpProtocol, UnitRule},
+        postgres_sink::TypeConflictPolicy,
+    },
+};
+
 #[derive(Debug, Deserialize, Parser, Clone)]
 #[clap(
     author = "Kenny",
@@ -10,7 +27,7 @@ use serde_derive::Deserialize;
         clap::ArgGroup::new("remote")
             .required(true)
             .multiple(true)
-            .args(["connection_string", "otlp_remote"]),
+            .args(["connection_string", "otlp_remote", "relay_upstream", "file_sink_directory", "parquet_directory", "prometheus_listen_address", "remote_write_endpoint", "clickhouse_endpoint", "sqlite_path", "debug_sink"]),
     )
 )]
 pub struct Options {
@@ -62,6 +79,23 @@ pub struct Options {
     )]
     pub api_keys: Vec<String>,
 
+    #[arg(
+        long,
+        help = "Tag metrics sent with an api key with its tenant, in --tenant-dimension. Clients can't set the tenant themselves once keys have tenants. Format: <api key>=<tenant>. Example: 0123abcd=staging",
+        env = "API_KEY_TENANTS",
+        value_delimiter = ',',
+        value_parser = parse_api_key_tenant,
+    )]
+    pub api_key_tenant: Vec<ApiKeyTenant>,
+
+    #[arg(
+        long,
+        help = "The dimension that says which tenant a metric belongs to",
+        default_value = "tenant",
+        env = "TENANT_DIMENSION"
+    )]
+    pub tenant_dimension: String,
+
     #[arg(
         long,
         help = "Example: 7d",
@@ -79,6 +113,58 @@ pub struct Options {
     )]
     pub compress_new_tables: bool,
 
+    #[arg(
+        long,
+        help = "Chunk interval for new metrics tables. Example: 4h",
+        default_value = "4h",
+        env = "TIMESCALE_CHUNK_INTERVAL",
+        value_parser = humantime::parse_duration,
+    )]
+    pub chunk_interval: Duration,
+
+    #[arg(
+        long,
+        help = "How old chunks get before the compression policy compresses them. Example: 4h",
+        default_value = "4h",
+        env = "TIMESCALE_COMPRESS_AFTER",
+        value_parser = humantime::parse_duration,
+    )]
+    pub compress_after: Duration,
+
+    #[arg(
+        long,
+        help = "Table settings for metrics matching a name or a prefix ending in *, over the global ones. Settings are chunk, retention, compress, compress_after, segmentby (columns joined by +) and partition (<column>/<partitions>). The first matching rule wins. Example: api_*:chunk=30m;retention=14d;segmentby=host+region;partition=host/4",
+        env = "TIMESCALE_TABLE_POLICIES",
+        value_delimiter = ',',
+        value_parser = parse_table_policy_rule,
+    )]
+    pub table_policy: Vec<TablePolicyRule>,
+
+    #[arg(
+        long,
+        help = "Update existing tables' chunk interval, retention and compression to their policy the first time goodmetricsd writes to them",
+        env = "TIMESCALE_RECONCILE_POLICIES"
+    )]
+    pub reconcile_table_policies: bool,
+
+    #[arg(
+        long,
+        help = "Create a continuous aggregate per bucket width for metrics tables, grouped by every dimension. When columns are added, the old one is kept as <view>_v<n> until its retention runs out. Example: 1m,1h",
+        env = "TIMESCALE_CONTINUOUS_AGGREGATES",
+        value_delimiter = ',',
+        value_parser = humantime::parse_duration,
+    )]
+    pub continuous_aggregate: Vec<Duration>,
+
+    #[arg(
+        long,
+        help = "Retention for continuous aggregates. Example: 90d",
+        default_value = "90d",
+        env = "TIMESCALE_CONTINUOUS_AGGREGATE_RETENTION",
+        value_parser = humantime::parse_duration,
+    )]
+    pub continuous_aggregate_retention: Duration,
+
     #[arg(
         long,
         help = "Example: host=localhost port=2345 user=metrics password=metrics connect_timeout=10",
@@ -86,6 +172,66 @@ pub struct Options {
     )]
     pub connection_string: Option<String>,
 
+    #[arg(
+        long,
+        help = "Like libpq's sslmode, including verify-ca and verify-full. Defaults to the connection string's sslmode",
+        env = "TIMESCALE_SSL_MODE",
+        value_enum
+    )]
+    pub postgres_ssl_mode: Option<PostgresSslMode>,
+
+    #[arg(
+        long,
+        help = "File path to the pem root certificates to verify postgres with. Defaults to the platform's roots",
+        env = "TIMESCALE_SSL_ROOT_CERT"
+    )]
+    pub postgres_ssl_root_certificate: Option<String>,
+
+    #[arg(
+        long,
+        help = "File path to a pem client certificate chain for postgres",
+        env = "TIMESCALE_SSL_CERT"
+    )]
+    pub postgres_ssl_client_certificate: Option<String>,
+
+    #[arg(
+        long,
+        help = "File path to the pem private key for the postgres client certificate",
+        env = "TIMESCALE_SSL_KEY"
+    )]
+    pub postgres_ssl_client_private_key: Option<String>,
+
+    #[arg(
+        long,
+        help = "What to do when a measurement or dimension changes type but keeps its name, tried in order until one works. widen: change int4, int8, float4 columns in place to a type that holds both. rename: move the old column to <column>_<oldtype>, numbered if that's taken, and make a new one. The time column is never widened or renamed. quarantine: send the conflicting rows to <table>_quarantine. When none apply, the batch is dropped. Example: widen,rename",
+        env = "TIMESCALE_TYPE_CONFLICT",
+        value_enum,
+        value_delimiter = ','
+    )]
+    pub postgres_type_conflict: Vec<TypeConflictPolicy>,
+
+    #[arg(
+        long,
+        help = "Schema for metrics tables, created if it's missing. Defaults to the connection's search_path. Example: staging",
+        env = "TIMESCALE_SCHEMA"
+    )]
+    pub postgres_schema: Option<String>,
+
+    #[arg(
+        long,
+        help = "Prefix for metrics table names. Example: app_",
+        default_value = "",
+        env = "TIMESCALE_TABLE_PREFIX"
+    )]
+    pub postgres_table_prefix: String,
+
+    #[arg(
+        long,
+        help = "Write each tenant's metrics to a schema named for its --tenant-dimension, created if it's missing. The tenant isn't stored as a column. Metrics without a tenant go to --postgres-schema. Needs --api-key-tenant so clients can't pick their own schema",
+        env = "TIMESCALE_TENANT_SCHEMAS"
+    )]
+    pub postgres_tenant_schemas: bool,
+
     #[arg(
         long,
         help = "Send dumbed down metrics via otel metrics format. Example: https://my.opentelemetry:4317",
@@ -99,6 +245,423 @@ pub struct Options {
         env = "OTLP_INSECURE"
     )]
     pub otlp_insecure: bool,
+
+    #[arg(
+        long,
+        help = "How to send to the opentelemetry remote. The http protocols POST to <otlp-remote>/v1/metrics.",
+        value_enum,
+        default_value = "grpc",
+        env = "OTEL_EXPORTER_OTLP_PROTOCOL"
+    )]
+    pub otlp_protocol: OtlpProtocol,
+
+    #[arg(
+        long,
+        help = "Quantiles to compute from t-digest centroids for opentelemetry summaries. Min and max are always sent as 0 and 1.",
+        default_value = "0.5,0.9,0.99",
+        env = "OTLP_TDIGEST_QUANTILES",
+        value_delimiter = ','
+    )]
+    pub otlp_tdigest_quantiles: Vec<f64>,
+
+    #[arg(
+        long,
+        help = "Also send t-digests as explicit-bucket histograms named {metric}_{measurement}_histogram, with these bucket bounds. Example: 1,5,10,50,100,500",
+        env = "OTLP_TDIGEST_HISTOGRAM_BOUNDS",
+        value_delimiter = ','
+    )]
+    pub otlp_tdigest_histogram_bounds: Vec<f64>,
+
+    #[arg(
+        long,
+        help = "Send t-digests to opentelemetry as exponential histograms instead of summaries",
+        env = "OTLP_EXPONENTIAL_HISTOGRAMS"
+    )]
+    pub otlp_exponential_histograms: bool,
+
+    #[arg(
+        long,
+        help = "service.name resource attribute for opentelemetry",
+        default_value = "goodmetricsd",
+        env = "OTEL_SERVICE_NAME"
+    )]
+    pub otlp_service_name: String,
+
+    #[arg(
+        long,
+        help = "deployment.environment resource attribute for opentelemetry. Example: production",
+        env = "OTLP_DEPLOYMENT_ENVIRONMENT"
+    )]
+    pub otlp_deployment_environment: Option<String>,
+
+    #[arg(
+        long,
+        help = "host.name resource attribute for opentelemetry",
+        env = "OTLP_HOST_NAME"
+    )]
+    pub otlp_host_name: Option<String>,
+
+    #[arg(
+        long,
+        help = "More opentelemetry resource attributes. These win over the named ones. Example: service.namespace=metrics,cloud.region=us-west-2",
+        env = "OTEL_RESOURCE_ATTRIBUTES",
+        value_delimiter = ','
+    )]
+    pub otlp_resource_attribute: Vec<String>,
+
+    #[arg(
+        long,
+        help = "How long to collect datums for before exporting to opentelemetry",
+        default_value = "5s",
+        env = "OTLP_BATCH_WINDOW",
+        value_parser = humantime::parse_duration,
+    )]
+    pub otlp_batch_window: Duration,
+
+    #[arg(
+        long,
+        help = "Split opentelemetry exports that would carry more data points than this",
+        default_value = "10000",
+        env = "OTLP_MAX_DATA_POINTS_PER_EXPORT"
+    )]
+    pub otlp_max_data_points_per_export: usize,
+
+    #[arg(
+        long,
+        help = "gRPC metadata or http headers to send with each opentelemetry export. Example: x-api-key=abc123",
+        env = "OTLP_HEADERS",
+        value_delimiter = ','
+    )]
+    pub otlp_header: Vec<String>,
+
+    #[arg(
+        long,
+        help = "How many times to retry an opentelemetry export that failed with a retryable status",
+        default_value = "5",
+        env = "OTLP_MAX_RETRIES"
+    )]
+    pub otlp_max_retries: u32,
+
+    #[arg(
+        long,
+        help = "Path to a pem certificate chain for opentelemetry remotes that want mutual tls",
+        env = "OTLP_CLIENT_CERTIFICATE"
+    )]
+    pub otlp_client_certificate: Option<String>,
+
+    #[arg(
+        long,
+        help = "Path to the pem private key for the opentelemetry client certificate",
+        env = "OTLP_CLIENT_PRIVATE_KEY"
+    )]
+    pub otlp_client_private_key: Option<String>,
+
+    #[arg(
+        long,
+        help = "How to name opentelemetry metrics",
+        default_value = "{metric}_{measurement}",
+        env = "OTLP_NAME_FORMAT"
+    )]
+    pub otlp_name_format: String,
+
+    #[arg(
+        long,
+        help = "Send number measurements matching a name pattern as this kind, unless the client said otherwise. Kinds: gauge, delta_sum, cumulative_sum, monotonic_delta_sum, monotonic_cumulative_sum. Example: *_count=monotonic_delta_sum",
+        env = "OTLP_KIND_RULES",
+        value_delimiter = ',',
+        value_parser = parse_kind_rule,
+    )]
+    pub otlp_kind_rule: Vec<KindRule>,
+
+    #[arg(
+        long,
+        help = "Unit for opentelemetry metrics without a unit rule",
+        default_value = "1",
+        env = "OTLP_UNIT"
+    )]
+    pub otlp_unit: String,
+
+    #[arg(
+        long,
+        help = "Unit for measurements matching a name pattern. Example: *_ms=ms",
+        env = "OTLP_UNIT_RULES",
+        value_delimiter = ',',
+        value_parser = parse_unit_rule,
+    )]
+    pub otlp_unit_rule: Vec<UnitRule>,
+
+    #[arg(
+        long,
+        help = "Accumulate delta sums and histograms into cumulative ones, for opentelemetry backends that only accept cumulative temporality",
+        env = "OTLP_CUMULATIVE"
+    )]
+    pub otlp_cumulative: bool,
+
+    #[arg(
+        long,
+        help = "How long a cumulative series can go without data before it starts over",
+        default_value = "10m",
+        env = "OTLP_CUMULATIVE_SERIES_EXPIRY",
+        value_parser = humantime::parse_duration,
+    )]
+    pub otlp_cumulative_series_expiry: Duration,
+
+    #[arg(
+        long,
+        help = "Relay batches to upstream goodmetricsd instances. Metrics are sharded across upstreams by name. Example: https://central.goodmetrics:9573",
+        env = "RELAY_UPSTREAMS",
+        value_delimiter = ','
+    )]
+    pub relay_upstream: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Authorization token to send to the relay upstreams",
+        env = "RELAY_AUTHORIZATION"
+    )]
+    pub relay_authorization: Option<String>,
+
+    #[arg(
+        long,
+        help = "Skip server certificate verification for relay upstreams",
+        env = "RELAY_INSECURE"
+    )]
+    pub relay_insecure: bool,
+
+    #[arg(
+        long,
+        help = "How long to collect datums before relaying them. Example: 1s",
+        default_value = "1s",
+        env = "RELAY_BATCH_WINDOW",
+        value_parser = humantime::parse_duration,
+    )]
+    pub relay_batch_window: Duration,
+
+    #[arg(
+        long,
+        help = "Most datums to send to an upstream in 1 request",
+        default_value = "10000",
+        env = "RELAY_MAX_BATCH_SIZE"
+    )]
+    pub relay_max_batch_size: usize,
+
+    #[arg(
+        long,
+        help = "How many times to retry a failed relay request, with exponential backoff",
+        default_value = "5",
+        env = "RELAY_MAX_RETRIES"
+    )]
+    pub relay_max_retries: u32,
+
+    #[arg(
+        long,
+        help = "Append every datum to ndjson files in this directory, in the format `goodmetrics send` accepts",
+        env = "FILE_SINK_DIRECTORY"
+    )]
+    pub file_sink_directory: Option<String>,
+
+    #[arg(
+        long,
+        help = "Start a new file once the current one is this many bytes",
+        default_value = "268435456",
+        env = "FILE_SINK_MAX_SEGMENT_BYTES"
+    )]
+    pub file_sink_max_segment_bytes: u64,
+
+    #[arg(
+        long,
+        help = "Start a new file once the current one is this old. Example: 1h",
+        default_value = "1h",
+        env = "FILE_SINK_MAX_SEGMENT_AGE",
+        value_parser = humantime::parse_duration,
+    )]
+    pub file_sink_max_segment_age: Duration,
+
+    #[arg(long, help = "Gzip files once they are closed", env = "FILE_SINK_GZIP")]
+    pub file_sink_gzip: bool,
+
+    #[arg(
+        long,
+        help = "Write parquet files under <directory>/<metric>/<yyyy-mm-dd>/<hh>/ with the same wide schema as the timescale tables",
+        env = "PARQUET_DIRECTORY"
+    )]
+    pub parquet_directory: Option<String>,
+
+    #[arg(
+        long,
+        help = "How long to collect datums before writing parquet files. Longer makes fewer, bigger files. Example: 5m",
+        default_value = "1m",
+        env = "PARQUET_BATCH_WINDOW",
+        value_parser = humantime::parse_duration,
+    )]
+    pub parquet_batch_window: Duration,
+
+    #[arg(
+        long,
+        help = "Serve the latest value of each series on /metrics for prometheus to scrape. Example: 0.0.0.0:9574",
+        env = "PROMETHEUS_LISTEN_ADDRESS"
+    )]
+    pub prometheus_listen_address: Option<String>,
+
+    #[arg(
+        long,
+        help = "Stop serving series that have not been reported for this long. Example: 5m",
+        default_value = "5m",
+        env = "PROMETHEUS_STALENESS",
+        value_parser = humantime::parse_duration,
+    )]
+    pub prometheus_staleness: Duration,
+
+    #[arg(
+        long,
+        help = "Push datums to a prometheus remote_write endpoint. Example: https://mimir.example.com/api/v1/push",
+        env = "REMOTE_WRITE_ENDPOINT"
+    )]
+    pub remote_write_endpoint: Option<String>,
+
+    #[arg(
+        long,
+        help = "Extra http headers to send with remote writes. Example: X-Scope-OrgID=my_tenant",
+        env = "REMOTE_WRITE_HEADERS",
+        value_delimiter = ','
+    )]
+    pub remote_write_header: Vec<String>,
+
+    #[arg(
+        long,
+        help = "How long to collect datums before sending a remote write. Example: 5s",
+        default_value = "5s",
+        env = "REMOTE_WRITE_BATCH_WINDOW",
+        value_parser = humantime::parse_duration,
+    )]
+    pub remote_write_batch_window: Duration,
+
+    #[arg(
+        long,
+        help = "Start a new remote write request once this many samples are in the current one",
+        default_value = "5000",
+        env = "REMOTE_WRITE_MAX_SAMPLES_PER_REQUEST"
+    )]
+    pub remote_write_max_samples_per_request: usize,
+
+    #[arg(
+        long,
+        help = "How many times to retry a failed remote write, with exponential backoff",
+        default_value = "5",
+        env = "REMOTE_WRITE_MAX_RETRIES"
+    )]
+    pub remote_write_max_retries: u32,
+
+    #[arg(
+        long,
+        help = "Send metrics to clickhouse's http interface, with a table per metric. Example: http://localhost:8123",
+        env = "CLICKHOUSE_ENDPOINT"
+    )]
+    pub clickhouse_endpoint: Option<String>,
+
+    #[arg(
+        long,
+        help = "The clickhouse database to create metrics tables in",
+        default_value = "default",
+        env = "CLICKHOUSE_DATABASE"
+    )]
+    pub clickhouse_database: String,
+
+    #[arg(
+        long,
+        help = "User for the clickhouse http interface. Defaults to clickhouse's default user",
+        env = "CLICKHOUSE_USER"
+    )]
+    pub clickhouse_user: Option<String>,
+
+    #[arg(
+        long,
+        help = "Password for --clickhouse-user",
+        env = "CLICKHOUSE_PASSWORD"
+    )]
+    pub clickhouse_password: Option<String>,
+
+    #[arg(
+        long,
+        help = "Write a table per metric to a local sqlite database. Handy for seeing what your instrumentation emits. Example: ./metrics.sqlite",
+        env = "SQLITE_PATH"
+    )]
+    pub sqlite_path: Option<String>,
+
+    #[arg(
+        long,
+        help = "Print every batch to stdout with the column types the postgres sink would use. Run it alone to debug what a client sends.",
+        value_enum,
+        env = "DEBUG_SINK"
+    )]
+    pub debug_sink: Option<DebugFormat>,
+
+    #[arg(
+        long,
+        help = "Only print these metrics from the debug sink. A trailing * matches a prefix. Example: api_*",
+        env = "DEBUG_SINK_METRICS",
+        value_delimiter = ','
+    )]
+    pub debug_sink_metric: Vec<String>,
+
+    #[arg(
+        long,
+        help = "What to do with datums whose timestamps fall outside of the window around now",
+        value_enum,
+        default_value = "accept",
+        env = "TIMESTAMP_POLICY"
+    )]
+    pub timestamp_policy: TimestampAction,
+
+    #[arg(
+        long,
+        help = "How far in the past a datum's timestamp may be before the timestamp policy applies. Example: 1d",
+        default_value = "1d",
+        env = "TIMESTAMP_MAX_AGE",
+        value_parser = humantime::parse_duration,
+    )]
+    pub timestamp_max_age: Duration,
+
+    #[arg(
+        long,
+        help = "How far in the future a datum's timestamp may be before the timestamp policy applies. Example: 5m",
+        default_value = "5m",
+        env = "TIMESTAMP_MAX_FUTURE",
+        value_parser = humantime::parse_duration,
+    )]
+    pub timestamp_max_future: Duration,
+
+    #[arg(
+        long,
+        help = "When the timestamp policy clamps or rewrites a datum, record the original unix nanos in this dimension",
+        env = "TIMESTAMP_REWRITE_DIMENSION"
+    )]
+    pub timestamp_rewrite_dimension: Option<String>,
+
+    #[arg(
+        long,
+        help = "Keep only a fraction of a metric's datums. The first matching rule wins. Format: <metric or prefix*>=<rate>[@<key dimension>]. With a key dimension, sampling is consistent on a hash of that dimension's value. Example: api_request=0.01@request_id",
+        env = "SAMPLE_RULES",
+        value_delimiter = ',',
+        value_parser = parse_sample_rule,
+    )]
+    pub sample_rule: Vec<SampleRule>,
+
+    #[arg(
+        long,
+        help = "Record the inverse of the sample rate on kept datums in this f64 measurement, so sum(value * weight) approximates the unsampled total. Example: sample_weight",
+        env = "SAMPLE_WEIGHT_MEASUREMENT"
+    )]
+    pub sample_weight_measurement: Option<String>,
+
+    #[arg(
+        long,
+        help = "How often to report what the ingest policies did, as the goodmetricsd_ingest metric",
+        default_value = "60s",
+        env = "INGEST_STATS_INTERVAL",
+        value_parser = humantime::parse_duration,
+    )]
+    pub ingest_stats_interval: Duration,
 }
 
 pub fn get_args() -> Options {
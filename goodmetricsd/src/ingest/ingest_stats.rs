@@ -0,0 +1,86 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use communication::proto::goodmetrics::{dimension, measurement, Datum, Dimension, Measurement};
+
+use crate::sink::{metricssendqueue::MetricsSendQueue, MetricsSink};
+
+/// Counts what the ingest policies did to datums, per metric and per action.
+/// The counts are reported back through goodmetricsd's own queue, so they land
+/// wherever the rest of your metrics do.
+#[derive(Debug, Default)]
+pub struct IngestStats {
+    counts: Mutex<BTreeMap<(String, &'static str), u64>>,
+}
+
+impl IngestStats {
+    pub fn count(&self, metric: &str, action: &'static str) {
+        let mut counts = self
+            .counts
+            .lock()
+            .expect("ingest stats lock is not poisoned");
+        *counts.entry((metric.to_string(), action)).or_default() += 1;
+    }
+
+    pub fn take_datums(&self, unix_nanos: u64) -> Vec<Datum> {
+        let counts = std::mem::take(
+            &mut *self
+                .counts
+                .lock()
+                .expect("ingest stats lock is not poisoned"),
+        );
+        counts
+            .into_iter()
+            .map(|((metric, action), count)| Datum {
+                metric: "goodmetricsd_ingest".to_string(),
+                unix_nanos,
+                dimensions: HashMap::from([
+                    (
+                        "metric".to_string(),
+                        Dimension {
+                            value: Some(dimension::Value::String(metric)),
+                        },
+                    ),
+                    (
+                        "action".to_string(),
+                        Dimension {
+                            value: Some(dimension::Value::String(action.to_string())),
+                        },
+                    ),
+                ]),
+                measurements: HashMap::from([(
+                    "datums".to_string(),
+                    Measurement {
+                        value: Some(measurement::Value::I64(count as i64)),
+                        ..Default::default()
+                    },
+                )]),
+            })
+            .collect()
+    }
+
+    pub async fn report_forever(&self, queue: MetricsSendQueue, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let datums = self.take_datums(now_nanos());
+            if datums.is_empty() {
+                continue;
+            }
+            log::debug!("reporting {} ingest stats", datums.len());
+            if let Err(e) = queue.drain(datums) {
+                log::warn!("dropping ingest stats: {:?}", e);
+            }
+        }
+    }
+}
+
+pub fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0))
+        .as_nanos() as u64
+}
@@ -0,0 +1,4 @@
+pub mod ingest_stats;
+pub mod sampling;
+pub mod tenant;
+pub mod timestamp_policy;
@@ -0,0 +1,231 @@
+use communication::proto::goodmetrics::{dimension, measurement, Datum, Measurement};
+use rand::Rng;
+use serde_derive::Deserialize;
+
+use crate::config::options::Options;
+
+use super::ingest_stats::IngestStats;
+
+/// Keeps `rate` of a metric's datums. With a `key_dimension`, the decision is made
+/// from a hash of that dimension's value, so every goodmetricsd keeps the same keys.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SampleRule {
+    /// A metric name, or a prefix ending in `*`.
+    pub metric: String,
+    pub rate: f64,
+    pub key_dimension: Option<String>,
+}
+
+impl SampleRule {
+    fn matches(&self, metric: &str) -> bool {
+        match self.metric.strip_suffix('*') {
+            Some(prefix) => metric.starts_with(prefix),
+            None => self.metric == metric,
+        }
+    }
+
+    fn keep(&self, datum: &Datum) -> bool {
+        let key = self
+            .key_dimension
+            .as_ref()
+            .and_then(|name| datum.dimensions.get(name))
+            .and_then(|dimension| dimension.value.as_ref());
+        match key {
+            Some(value) => {
+                let hash = match value {
+                    dimension::Value::String(s) => fnv1a(s.as_bytes()),
+                    dimension::Value::Number(n) => fnv1a(&n.to_le_bytes()),
+                    dimension::Value::Boolean(b) => fnv1a(&[*b as u8]),
+                };
+                (hash as f64 / u64::MAX as f64) < self.rate
+            }
+            // Datums without the key dimension fall back to a coin toss
+            None => rand::thread_rng().gen::<f64>() < self.rate,
+        }
+    }
+}
+
+/// <metric>=<rate>[@<key_dimension>], like api_request=0.01@request_id or api_*=0.1
+pub fn parse_sample_rule(value: &str) -> Result<SampleRule, String> {
+    let (metric, rest) = value
+        .split_once('=')
+        .ok_or_else(|| format!("sample rule needs <metric>=<rate>: {value}"))?;
+    let (rate, key_dimension) = match rest.split_once('@') {
+        Some((rate, key_dimension)) => (rate, Some(key_dimension.to_string())),
+        None => (rest, None),
+    };
+    let rate: f64 = rate
+        .parse()
+        .map_err(|e| format!("sample rate is not a number: {e:?}"))?;
+    if rate == 0.0 || !(0.0..=1.0).contains(&rate) {
+        return Err(format!("sample rate must be in (0, 1]: {rate}"));
+    }
+
+    Ok(SampleRule {
+        metric: metric.to_string(),
+        rate,
+        key_dimension,
+    })
+}
+
+#[derive(Debug)]
+pub struct SamplingPolicy {
+    rules: Vec<SampleRule>,
+    weight_measurement: Option<String>,
+}
+
+impl SamplingPolicy {
+    pub fn new(options: &Options) -> Self {
+        Self {
+            rules: options.sample_rule.clone(),
+            weight_measurement: options
+                .sample_weight_measurement
+                .clone()
+                .filter(|m| !m.is_empty()),
+        }
+    }
+
+    /// Applies the first matching rule to each datum, removing the ones it samples out.
+    pub fn apply(&self, datums: &mut Vec<Datum>, stats: &IngestStats) {
+        if self.rules.is_empty() {
+            return;
+        }
+        datums.retain_mut(|datum| {
+            let rule = match self.rules.iter().find(|rule| rule.matches(&datum.metric)) {
+                Some(rule) => rule,
+                None => return true,
+            };
+            if !rule.keep(datum) {
+                stats.count(&datum.metric, "sampled_out");
+                return false;
+            }
+            if let Some(weight_measurement) = &self.weight_measurement {
+                // sum(value * sample_weight) approximates the total from before sampling
+                datum.measurements.insert(
+                    weight_measurement.clone(),
+                    Measurement {
+                        value: Some(measurement::Value::F64(1.0 / rule.rate)),
+                        ..Default::default()
+                    },
+                );
+            }
+            true
+        });
+    }
+}
+
+// Stable across processes and versions, unlike std's DefaultHasher.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use communication::proto::goodmetrics::{dimension, measurement, Datum, Dimension};
+
+    use crate::ingest::ingest_stats::IngestStats;
+
+    use super::{fnv1a, parse_sample_rule, SamplingPolicy};
+
+    fn datum(metric: &str, key: Option<&str>) -> Datum {
+        Datum {
+            metric: metric.to_string(),
+            dimensions: key
+                .map(|key| {
+                    HashMap::from([(
+                        "request_id".to_string(),
+                        Dimension {
+                            value: Some(dimension::Value::String(key.to_string())),
+                        },
+                    )])
+                })
+                .unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rules_parse_with_and_without_a_key() {
+        let rule = parse_sample_rule("api_*=0.25@request_id").expect("rule parses");
+        assert_eq!(rule.metric, "api_*");
+        assert_eq!(rule.rate, 0.25);
+        assert_eq!(rule.key_dimension.as_deref(), Some("request_id"));
+
+        let rule = parse_sample_rule("api_request=1").expect("rule parses");
+        assert_eq!(rule.rate, 1.0);
+        assert_eq!(rule.key_dimension, None);
+    }
+
+    #[test]
+    fn rates_outside_0_to_1_are_refused() {
+        assert!(parse_sample_rule("m=0").is_err());
+        assert!(parse_sample_rule("m=1.5").is_err());
+        assert!(parse_sample_rule("m=-0.5").is_err());
+        assert!(parse_sample_rule("m=NaN").is_err());
+        assert!(parse_sample_rule("m").is_err());
+    }
+
+    #[test]
+    fn prefix_rules_match_by_prefix() {
+        let rule = parse_sample_rule("api_*=0.5").expect("rule parses");
+        assert!(rule.matches("api_request"));
+        assert!(!rule.matches("db_query"));
+
+        let rule = parse_sample_rule("api=0.5").expect("rule parses");
+        assert!(rule.matches("api"));
+        assert!(!rule.matches("api_request"));
+    }
+
+    #[test]
+    fn a_rate_of_1_keeps_every_key() {
+        let rule = parse_sample_rule("m=1@request_id").expect("rule parses");
+        for key in ["a", "b", "c", "d", "e", "f"] {
+            assert!(rule.keep(&datum("m", Some(key))));
+        }
+    }
+
+    #[test]
+    fn keyed_decisions_follow_the_hash() {
+        let rule = parse_sample_rule("m=0.5@request_id").expect("rule parses");
+        for key in ["a", "b", "c", "d", "e", "f"] {
+            let kept = (fnv1a(key.as_bytes()) as f64 / u64::MAX as f64) < 0.5;
+            assert_eq!(rule.keep(&datum("m", Some(key))), kept, "key {key}");
+            // The same key always gets the same answer
+            assert_eq!(rule.keep(&datum("m", Some(key))), kept, "key {key}");
+        }
+    }
+
+    #[test]
+    fn kept_datums_carry_the_inverse_rate() {
+        let policy = SamplingPolicy {
+            rules: vec![parse_sample_rule("m=0.25@request_id").expect("rule parses")],
+            weight_measurement: Some("sample_weight".to_string()),
+        };
+        let keys: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+        let mut datums: Vec<Datum> = keys.iter().map(|key| datum("m", Some(key))).collect();
+        datums.push(datum("other", None));
+        policy.apply(&mut datums, &IngestStats::default());
+
+        let (other, sampled): (Vec<_>, Vec<_>) =
+            datums.iter().partition(|datum| datum.metric == "other");
+        assert_eq!(other.len(), 1);
+        assert!(other[0].measurements.is_empty());
+        assert!(!sampled.is_empty() && sampled.len() < 100);
+        for datum in sampled {
+            assert_eq!(
+                datum.measurements["sample_weight"].value,
+                Some(measurement::Value::F64(4.0))
+            );
+        }
+    }
+
+    #[test]
+    fn fnv1a_matches_the_reference() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+    }
+}
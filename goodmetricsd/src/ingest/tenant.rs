@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+use communication::proto::goodmetrics::{dimension, Datum, Dimension};
+use serde_derive::Deserialize;
+
+use crate::config::options::Options;
+
+/// Metrics sent with `api_key` belong to `tenant`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKeyTenant {
+    pub api_key: String,
+    pub tenant: String,
+}
+
+/// <api key>=<tenant>, like 0123abcd=staging
+pub fn parse_api_key_tenant(value: &str) -> Result<ApiKeyTenant, String> {
+    let (api_key, tenant) = value
+        .split_once('=')
+        .ok_or_else(|| "api key tenant needs <api key>=<tenant>".to_string())?;
+    if api_key.trim().is_empty() || tenant.trim().is_empty() {
+        return Err("api key tenant needs <api key>=<tenant>".to_string());
+    }
+
+    Ok(ApiKeyTenant {
+        api_key: api_key.trim().to_string(),
+        tenant: tenant.trim().to_string(),
+    })
+}
+
+#[derive(Debug)]
+pub struct TenantPolicy {
+    dimension: String,
+    tenants: HashMap<String, String>,
+}
+
+impl TenantPolicy {
+    pub fn new(options: &Options) -> Self {
+        Self {
+            dimension: options.tenant_dimension.clone(),
+            tenants: options
+                .api_key_tenant
+                .iter()
+                .map(|rule| (rule.api_key.clone(), rule.tenant.clone()))
+                .collect(),
+        }
+    }
+
+    /// Sets the tenant dimension from the request's api key. Once keys have tenants, clients
+    /// can't pick their own: datums from a key without one lose the dimension.
+    pub fn apply(&self, api_key: Option<&str>, datums: &mut [Datum]) {
+        if self.tenants.is_empty() {
+            return;
+        }
+        let tenant = api_key.and_then(|api_key| self.tenants.get(api_key.trim()));
+        for datum in datums {
+            match tenant {
+                Some(tenant) => {
+                    datum.dimensions.insert(
+                        self.dimension.clone(),
+                        Dimension {
+                            value: Some(dimension::Value::String(tenant.clone())),
+                        },
+                    );
+                }
+                None => {
+                    datum.dimensions.remove(&self.dimension);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::time::Duration;
+
+use clap::ValueEnum;
+use communication::proto::goodmetrics::{dimension, Datum, Dimension};
+use serde_derive::Deserialize;
+
+use crate::config::options::Options;
+
+use super::ingest_stats::IngestStats;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+pub enum TimestampAction {
+    /// Trust whatever the client says, even 1970.
+    Accept,
+    /// Drop datums outside of the window.
+    Reject,
+    /// Move datums outside of the window to the nearest edge of the window.
+    Clamp,
+    /// Replace the timestamp of datums outside of the window with the server's time.
+    Rewrite,
+}
+
+#[derive(Debug)]
+pub struct TimestampPolicy {
+    action: TimestampAction,
+    max_age: Duration,
+    max_future: Duration,
+    rewrite_dimension: Option<String>,
+}
+
+impl TimestampPolicy {
+    pub fn new(options: &Options) -> Self {
+        Self {
+            action: options.timestamp_policy,
+            max_age: options.timestamp_max_age,
+            max_future: options.timestamp_max_future,
+            rewrite_dimension: options
+                .timestamp_rewrite_dimension
+                .clone()
+                .filter(|d| !d.is_empty()),
+        }
+    }
+
+    /// Applies the policy to a request's datums, removing the ones it rejects.
+    pub fn apply(&self, datums: &mut Vec<Datum>, now_nanos: u64, stats: &IngestStats) {
+        if self.action == TimestampAction::Accept {
+            return;
+        }
+        let earliest = now_nanos.saturating_sub(self.max_age.as_nanos() as u64);
+        let latest = now_nanos.saturating_add(self.max_future.as_nanos() as u64);
+
+        datums.retain_mut(|datum| {
+            let original = datum.unix_nanos;
+            if (earliest..=latest).contains(&original) {
+                return true;
+            }
+            datum.unix_nanos = match self.action {
+                TimestampAction::Accept => return true,
+                TimestampAction::Reject => {
+                    stats.count(&datum.metric, "timestamp_rejected");
+                    return false;
+                }
+                TimestampAction::Clamp => {
+                    stats.count(&datum.metric, "timestamp_clamped");
+                    original.clamp(earliest, latest)
+                }
+                TimestampAction::Rewrite => {
+                    stats.count(&datum.metric, "timestamp_rewritten");
+                    now_nanos
+                }
+            };
+            if let Some(dimension_name) = &self.rewrite_dimension {
+                // The original timestamp goes along for the ride so you can find your bad clocks.
+                datum.dimensions.insert(
+                    dimension_name.clone(),
+                    Dimension {
+                        value: Some(dimension::Value::Number(original)),
+                    },
+                );
+            }
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use communication::proto::goodmetrics::{dimension, Datum};
+
+    use crate::ingest::ingest_stats::IngestStats;
+
+    use super::{TimestampAction, TimestampPolicy};
+
+    const NOW: u64 = 1_000_000_000_000;
+    const SECOND: u64 = 1_000_000_000;
+
+    fn policy(action: TimestampAction, rewrite_dimension: Option<&str>) -> TimestampPolicy {
+        TimestampPolicy {
+            action,
+            max_age: Duration::from_secs(60),
+            max_future: Duration::from_secs(10),
+            rewrite_dimension: rewrite_dimension.map(str::to_string),
+        }
+    }
+
+    fn datums(unix_nanos: &[u64]) -> Vec<Datum> {
+        unix_nanos
+            .iter()
+            .map(|unix_nanos| Datum {
+                metric: "m".to_string(),
+                unix_nanos: *unix_nanos,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn times(datums: &[Datum]) -> Vec<u64> {
+        datums.iter().map(|datum| datum.unix_nanos).collect()
+    }
+
+    #[test]
+    fn window_edges_are_inside() {
+        let mut batch = datums(&[NOW - 60 * SECOND, NOW + 10 * SECOND]);
+        policy(TimestampAction::Reject, None).apply(&mut batch, NOW, &IngestStats::default());
+
+        assert_eq!(times(&batch), vec![NOW - 60 * SECOND, NOW + 10 * SECOND]);
+    }
+
+    #[test]
+    fn reject_drops_stale_and_future_datums() {
+        let mut batch = datums(&[NOW - 61 * SECOND, NOW, NOW + 11 * SECOND]);
+        policy(TimestampAction::Reject, None).apply(&mut batch, NOW, &IngestStats::default());
+
+        assert_eq!(times(&batch), vec![NOW]);
+    }
+
+    #[test]
+    fn clamp_moves_datums_to_the_nearest_edge() {
+        let mut batch = datums(&[1, NOW + 3600 * SECOND]);
+        policy(TimestampAction::Clamp, None).apply(&mut batch, NOW, &IngestStats::default());
+
+        assert_eq!(times(&batch), vec![NOW - 60 * SECOND, NOW + 10 * SECOND]);
+    }
+
+    #[test]
+    fn rewrite_uses_now_and_keeps_the_original() {
+        let mut batch = datums(&[1, NOW]);
+        policy(TimestampAction::Rewrite, Some("original_time")).apply(
+            &mut batch,
+            NOW,
+            &IngestStats::default(),
+        );
+
+        assert_eq!(times(&batch), vec![NOW, NOW]);
+        assert_eq!(
+            batch[0].dimensions["original_time"].value,
+            Some(dimension::Value::Number(1))
+        );
+        assert!(batch[1].dimensions.is_empty());
+    }
+
+    #[test]
+    fn accept_keeps_everything() {
+        let mut batch = datums(&[0, u64::MAX]);
+        policy(TimestampAction::Accept, None).apply(&mut batch, NOW, &IngestStats::default());
+
+        assert_eq!(times(&batch), vec![0, u64::MAX]);
+    }
+
+    #[test]
+    fn window_saturates_near_the_epoch() {
+        let mut batch = datums(&[0]);
+        policy(TimestampAction::Reject, None).apply(
+            &mut batch,
+            5 * SECOND,
+            &IngestStats::default(),
+        );
+
+        assert_eq!(times(&batch), vec![0]);
+    }
+}
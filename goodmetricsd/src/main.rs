@@ -1,12 +1,25 @@
 use communication::proto::goodmetrics::metrics_server::MetricsServer;
 use config::options::Options;
+use ingest::ingest_stats::IngestStats;
+use ingest::sampling::SamplingPolicy;
+use ingest::tenant::TenantPolicy;
+use ingest::timestamp_policy::TimestampPolicy;
+use sink::clickhouse_sink::ClickhouseSender;
+use sink::debug_sink::DebugSender;
+use sink::file_sink::FileSender;
 use sink::metricssendqueue::{MetricsReceiveQueue, MetricsSendQueue};
 use sink::opentelemetry_sink::OtelSender;
+use sink::parquet_sink::ParquetSender;
 use sink::postgres_sink::PostgresSender;
+use sink::prometheus_sink::PrometheusExporter;
+use sink::relay_sink::RelaySender;
+use sink::remote_write_sink::RemoteWriteSender;
 use sink::sink_error::SinkError;
+use sink::sqlite_sink::SqliteSender;
 use tonic::transport::{Identity, Server, ServerTlsConfig};
 
 use std::collections::HashSet;
+use std::sync::Arc;
 use std::{cmp::min, net::SocketAddr};
 use tokio::net::TcpListener;
 
@@ -14,6 +27,7 @@ use crate::config::options::get_args;
 use crate::servers::goodmetrics::GoodmetricsServer;
 
 mod config;
+mod ingest;
 mod postgres_things;
 mod servers;
 mod sink;
@@ -21,6 +35,10 @@ mod sink;
 async fn serve(
     args: Options,
     send_queue: MetricsSendQueue,
+    timestamp_policy: Arc<TimestampPolicy>,
+    sampling_policy: Arc<SamplingPolicy>,
+    tenant_policy: Arc<TenantPolicy>,
+    ingest_stats: Arc<IngestStats>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let address: std::net::SocketAddr = args.listen_socket_address.parse()?;
     let socket = socket2::Socket::new(
@@ -43,6 +61,10 @@ async fn serve(
 
     let one_server_thread = GoodmetricsServer {
         metrics_sink: send_queue,
+        timestamp_policy,
+        sampling_policy,
+        tenant_policy,
+        ingest_stats,
     };
 
     let identity = get_identity(&args).await?;
@@ -147,10 +169,18 @@ async fn run_server(args: Options) {
     let mut handlers = Vec::new();
     let args_shared = args;
     let (send_queue, receive_queue) = MetricsSendQueue::new();
+    let timestamp_policy = Arc::new(TimestampPolicy::new(&args_shared));
+    let sampling_policy = Arc::new(SamplingPolicy::new(&args_shared));
+    let tenant_policy = Arc::new(TenantPolicy::new(&args_shared));
+    let ingest_stats = Arc::new(IngestStats::default());
 
     for i in 0..min(args_shared.max_threads, num_cpus::get()) {
         let threadlocal_args = args_shared.clone();
         let thread_send_queue = send_queue.clone();
+        let thread_timestamp_policy = timestamp_policy.clone();
+        let thread_sampling_policy = sampling_policy.clone();
+        let thread_tenant_policy = tenant_policy.clone();
+        let thread_ingest_stats = ingest_stats.clone();
 
         let h = std::thread::spawn(move || {
             log::info!(
@@ -163,12 +193,33 @@ async fn run_server(args: Options) {
                 .enable_all()
                 .build()
                 .expect("runtime can be made")
-                .block_on(serve(threadlocal_args, thread_send_queue))
+                .block_on(serve(
+                    threadlocal_args,
+                    thread_send_queue,
+                    thread_timestamp_policy,
+                    thread_sampling_policy,
+                    thread_tenant_policy,
+                    thread_ingest_stats,
+                ))
                 .expect("server completes");
         });
         handlers.push(h);
     }
 
+    {
+        let stats_queue = send_queue.clone();
+        let stats_interval = args_shared.ingest_stats_interval;
+        let bg_handle = std::thread::spawn(move || {
+            // Report what the ingest policies did on a background task
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("runtime can be made")
+                .block_on(ingest_stats.report_forever(stats_queue, stats_interval));
+        });
+        handlers.push(bg_handle);
+    }
+
     if let Some(connection_string_arg) = &args_shared.connection_string {
         let connection_string = connection_string_arg.clone();
         let threadlocal_args = args_shared.clone();
@@ -188,24 +239,160 @@ async fn run_server(args: Options) {
         handlers.push(bg_handle);
     }
 
-    let insecure_otlp = args_shared.otlp_insecure;
     if let Some(otlp_remote_arg) = &args_shared.otlp_remote {
         let cloned_queue = MetricsReceiveQueue {
             rx: send_queue.tx.subscribe(),
         };
         let otlp_remote = otlp_remote_arg.clone();
+        let threadlocal_args = args_shared.clone();
         let bg_handle = std::thread::spawn(move || {
             // Consume stuff on a background task
             tokio::runtime::Builder::new_current_thread()
                 .enable_all()
                 .build()
                 .expect("runtime can be made")
-                .block_on(consume_otel(otlp_remote, cloned_queue, insecure_otlp))
+                .block_on(consume_otel(otlp_remote, cloned_queue, threadlocal_args))
                 .expect("otel sender completes");
         });
         handlers.push(bg_handle);
     }
 
+    if !args_shared.relay_upstream.is_empty() {
+        let cloned_queue = MetricsReceiveQueue {
+            rx: send_queue.tx.subscribe(),
+        };
+        let threadlocal_args = args_shared.clone();
+        let bg_handle = std::thread::spawn(move || {
+            // Consume stuff on a background task
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("runtime can be made")
+                .block_on(consume_relay(cloned_queue, threadlocal_args))
+                .expect("relay sender completes");
+        });
+        handlers.push(bg_handle);
+    }
+
+    if args_shared.file_sink_directory.is_some() {
+        let cloned_queue = MetricsReceiveQueue {
+            rx: send_queue.tx.subscribe(),
+        };
+        let threadlocal_args = args_shared.clone();
+        let bg_handle = std::thread::spawn(move || {
+            // Consume stuff on a background task
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("runtime can be made")
+                .block_on(consume_file(cloned_queue, threadlocal_args))
+                .expect("file sender completes");
+        });
+        handlers.push(bg_handle);
+    }
+
+    if args_shared.parquet_directory.is_some() {
+        let cloned_queue = MetricsReceiveQueue {
+            rx: send_queue.tx.subscribe(),
+        };
+        let threadlocal_args = args_shared.clone();
+        let bg_handle = std::thread::spawn(move || {
+            // Consume stuff on a background task
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("runtime can be made")
+                .block_on(ParquetSender::new(cloned_queue, threadlocal_args).consume_stuff())
+                .expect("parquet sender completes");
+        });
+        handlers.push(bg_handle);
+    }
+
+    if args_shared.prometheus_listen_address.is_some() {
+        let cloned_queue = MetricsReceiveQueue {
+            rx: send_queue.tx.subscribe(),
+        };
+        let threadlocal_args = args_shared.clone();
+        let bg_handle = std::thread::spawn(move || {
+            // Consume stuff on a background task
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("runtime can be made")
+                .block_on(consume_prometheus(cloned_queue, threadlocal_args))
+                .expect("prometheus exporter completes");
+        });
+        handlers.push(bg_handle);
+    }
+
+    if args_shared.remote_write_endpoint.is_some() {
+        let cloned_queue = MetricsReceiveQueue {
+            rx: send_queue.tx.subscribe(),
+        };
+        let threadlocal_args = args_shared.clone();
+        let bg_handle = std::thread::spawn(move || {
+            // Consume stuff on a background task
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("runtime can be made")
+                .block_on(consume_remote_write(cloned_queue, threadlocal_args))
+                .expect("remote write sender completes");
+        });
+        handlers.push(bg_handle);
+    }
+
+    if args_shared.clickhouse_endpoint.is_some() {
+        let cloned_queue = MetricsReceiveQueue {
+            rx: send_queue.tx.subscribe(),
+        };
+        let threadlocal_args = args_shared.clone();
+        let bg_handle = std::thread::spawn(move || {
+            // Consume stuff on a background task
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("runtime can be made")
+                .block_on(consume_clickhouse(cloned_queue, threadlocal_args))
+                .expect("clickhouse sender completes");
+        });
+        handlers.push(bg_handle);
+    }
+
+    if args_shared.sqlite_path.is_some() {
+        let cloned_queue = MetricsReceiveQueue {
+            rx: send_queue.tx.subscribe(),
+        };
+        let threadlocal_args = args_shared.clone();
+        let bg_handle = std::thread::spawn(move || {
+            // Consume stuff on a background task
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("runtime can be made")
+                .block_on(consume_sqlite(cloned_queue, threadlocal_args))
+                .expect("sqlite sender completes");
+        });
+        handlers.push(bg_handle);
+    }
+
+    if args_shared.debug_sink.is_some() {
+        let cloned_queue = MetricsReceiveQueue {
+            rx: send_queue.tx.subscribe(),
+        };
+        let threadlocal_args = args_shared.clone();
+        let bg_handle = std::thread::spawn(move || {
+            // Consume stuff on a background task
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("runtime can be made")
+                .block_on(DebugSender::new(cloned_queue, threadlocal_args).consume_stuff())
+                .expect("debug sender completes");
+        });
+        handlers.push(bg_handle);
+    }
+
     for h in handlers {
         h.join().expect("all handles join gracefully");
     }
@@ -231,10 +418,10 @@ async fn consume_postgres(
 async fn consume_otel(
     opentelemetry_endpoint: String,
     receive_queue: MetricsReceiveQueue,
-    insecure: bool,
+    options: Options,
 ) -> Result<(), SinkError> {
     let sender =
-        match OtelSender::new_connection(&opentelemetry_endpoint, receive_queue, insecure).await {
+        match OtelSender::new_connection(&opentelemetry_endpoint, receive_queue, options).await {
             Ok(sender) => sender,
             Err(e) => {
                 log::error!("failed to start otel sender: {:?}", e);
@@ -244,3 +431,93 @@ async fn consume_otel(
     sender.consume_stuff().await?;
     Ok(())
 }
+
+async fn consume_relay(
+    receive_queue: MetricsReceiveQueue,
+    options: Options,
+) -> Result<(), SinkError> {
+    let sender = match RelaySender::new_connection(receive_queue, options).await {
+        Ok(sender) => sender,
+        Err(e) => {
+            log::error!("failed to start relay sender: {:?}", e);
+            std::process::exit(3)
+        }
+    };
+    sender.consume_stuff().await?;
+    Ok(())
+}
+
+async fn consume_file(
+    receive_queue: MetricsReceiveQueue,
+    options: Options,
+) -> Result<(), SinkError> {
+    let sender = match FileSender::new(receive_queue, options).await {
+        Ok(sender) => sender,
+        Err(e) => {
+            log::error!("failed to start file sender: {:?}", e);
+            std::process::exit(3)
+        }
+    };
+    sender.consume_stuff().await?;
+    Ok(())
+}
+
+async fn consume_prometheus(
+    receive_queue: MetricsReceiveQueue,
+    options: Options,
+) -> Result<(), SinkError> {
+    let exporter = match PrometheusExporter::new(receive_queue, options) {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            log::error!("failed to start prometheus exporter: {:?}", e);
+            std::process::exit(3)
+        }
+    };
+    exporter.consume_stuff().await?;
+    Ok(())
+}
+
+async fn consume_remote_write(
+    receive_queue: MetricsReceiveQueue,
+    options: Options,
+) -> Result<(), SinkError> {
+    let sender = match RemoteWriteSender::new(receive_queue, options) {
+        Ok(sender) => sender,
+        Err(e) => {
+            log::error!("failed to start remote write sender: {:?}", e);
+            std::process::exit(3)
+        }
+    };
+    sender.consume_stuff().await?;
+    Ok(())
+}
+
+async fn consume_clickhouse(
+    receive_queue: MetricsReceiveQueue,
+    options: Options,
+) -> Result<(), SinkError> {
+    let sender = match ClickhouseSender::new(receive_queue, options) {
+        Ok(sender) => sender,
+        Err(e) => {
+            log::error!("failed to start clickhouse sender: {:?}", e);
+            std::process::exit(3)
+        }
+    };
+    sender.consume_stuff().await?;
+    Ok(())
+}
+
+async fn consume_sqlite(
+    receive_queue: MetricsReceiveQueue,
+    options: Options,
+) -> Result<(), SinkError> {
+    let sender = match SqliteSender::new(receive_queue, options) {
+        Ok(sender) => sender,
+        Err(e) => {
+            log::error!("failed to start sqlite sender: {:?}", e);
+            std::process::exit(3)
+        }
+    };
+    sender.consume_stuff().await?;
+    Ok(())
+}
@@ -0,0 +1,234 @@
+use std::{collections::BTreeSet, time::Duration};
+
+use tokio_postgres::Client;
+
+use super::ddl::{self, clean_id, with_suffix};
+
+/// A metric table's rollup column: each measurement becomes the same kind of aggregate the
+/// dashboards already combine, so rollups of rollups work.
+pub struct Rollup {
+    pub column: String,
+    pub data_type: String,
+}
+
+impl Rollup {
+    fn expression(&self) -> Option<String> {
+        let column = &self.column;
+        match self.data_type.as_str() {
+            "int4" | "int8" | "float4" | "float8" => {
+                Some(format!("accumulate({column}::float8) AS {column}"))
+            }
+            "statistic_set" => Some(format!("accumulate({column}) AS {column}")),
+            "histogram" => Some(format!("accumulate_seh({column}) AS {column}")),
+            "tdigest" => Some(format!("rollup({column}) AS {column}")),
+            _ => None,
+        }
+    }
+}
+
+pub fn continuous_aggregate_name(table_name: &str, bucket: &Duration) -> String {
+    let suffix = format!(
+        "_{}",
+        clean_id(&humantime::format_duration(*bucket).to_string())
+    );
+    match table_name.split_once('.') {
+        Some((schema, table)) => format!("{schema}.{}", with_suffix(table, &suffix)),
+        None => with_suffix(table_name, &suffix),
+    }
+}
+
+/// The view's name without its schema, and the name it's built under before it replaces the view
+fn view_names(view: &str) -> (String, String) {
+    match view.split_once('.') {
+        Some((schema, name)) => (
+            name.to_string(),
+            format!("{schema}.{}", with_suffix(name, "_next")),
+        ),
+        None => (view.to_string(), with_suffix(view, "_next")),
+    }
+}
+
+/// A replaced view keeps its history under <view>_v<n>
+fn archived_name(view: &str, n: usize) -> String {
+    match view.split_once('.') {
+        Some((schema, name)) => format!("{schema}.{}", with_suffix(name, &format!("_v{n}"))),
+        None => with_suffix(view, &format!("_v{n}")),
+    }
+}
+
+/// Every continuous aggregate in the table's schema, named like the table is
+async fn existing_continuous_aggregates(
+    client: &Client,
+    table_name: &str,
+) -> Result<BTreeSet<String>, tokio_postgres::Error> {
+    let schema = table_name.split_once('.').map(|(schema, _)| schema);
+    let rows = client
+        .query(
+            "select view_schema::text, view_name::text from timescaledb_information.continuous_aggregates where view_schema = coalesce($1::text, current_schema())",
+            &[&schema],
+        )
+        .await?;
+    Ok(rows
+        .iter()
+        .map(|row| match schema {
+            Some(_) => format!("{}.{}", row.get::<_, String>(0), row.get::<_, String>(1)),
+            None => row.get(1),
+        })
+        .collect())
+}
+
+/// Creates a continuous aggregate per bucket grouped by every dimension, or replaces one whose
+/// columns no longer match. Views that already match are left alone.
+/// A replacement is built under a staging name and swapped in, so a failure leaves the old one in
+/// place and returns false for the caller to try again later. It fills from the raw rows still
+/// retained in the background, rather than on the ingest path.
+/// The view it replaces isn't dropped: it stops refreshing and keeps its older rollups as
+/// <view>_v<n> until its retention empties it, and then it's dropped.
+/// Timescale won't create them in a transaction, so each statement runs on its own.
+#[allow(clippy::too_many_arguments)]
+pub async fn build_continuous_aggregates(
+    client: &Client,
+    table_name: &str,
+    buckets: &[Duration],
+    table_retention: &Duration,
+    retention: &Duration,
+    dimensions: &[String],
+    rollups: &[Rollup],
+) -> bool {
+    let group_columns: String = dimensions
+        .iter()
+        .map(|dimension| format!(", {dimension}"))
+        .collect();
+    let rollup_columns: String = rollups
+        .iter()
+        .filter_map(Rollup::expression)
+        .map(|expression| format!(", {expression}"))
+        .collect();
+    let wanted_columns: BTreeSet<&str> = std::iter::once("time")
+        .chain(dimensions.iter().map(String::as_str))
+        .chain(
+            rollups
+                .iter()
+                .filter(|rollup| rollup.expression().is_some())
+                .map(|rollup| rollup.column.as_str()),
+        )
+        .collect();
+
+    let mut aggregates = match existing_continuous_aggregates(client, table_name).await {
+        Ok(aggregates) => aggregates,
+        Err(e) => {
+            log::error!("could not list continuous aggregates for {table_name}: {e:?}");
+            return false;
+        }
+    };
+
+    let mut built = true;
+    for bucket in buckets {
+        let view = continuous_aggregate_name(table_name, bucket);
+        let (view_name, staging) = view_names(&view);
+        drop_emptied_archives(client, &view, &mut aggregates).await;
+
+        let existing_columns = match ddl::column_types(client, &view).await {
+            Ok(columns) => columns,
+            Err(e) => {
+                log::error!("could not read continuous aggregate {view}: {e:?}");
+                built = false;
+                continue;
+            }
+        };
+        if !existing_columns.is_empty()
+            && existing_columns
+                .keys()
+                .map(String::as_str)
+                .eq(wanted_columns.iter().copied())
+        {
+            continue;
+        }
+
+        let bucket_seconds = bucket.as_secs().max(1);
+        // The refresh window has to cover at least 2 buckets, and the newest bucket is still filling.
+        // It stays inside the aggregate's retention so refreshes don't bring back dropped rollups.
+        let start_seconds = table_retention
+            .as_secs()
+            .min(retention.as_secs().saturating_sub(bucket_seconds))
+            .max(bucket_seconds * 3);
+        let mut statements = vec![
+            format!("DROP MATERIALIZED VIEW IF EXISTS {staging};"),
+            format!(
+                r#"CREATE MATERIALIZED VIEW {staging} WITH (timescaledb.continuous) AS
+                SELECT time_bucket(INTERVAL '{bucket_seconds} seconds', time) AS time{group_columns}{rollup_columns}
+                FROM {table_name}
+                GROUP BY 1{group_columns}
+                WITH NO DATA;"#
+            ),
+            format!(
+                "SELECT add_continuous_aggregate_policy('{staging}', start_offset => INTERVAL '{start_seconds} seconds', end_offset => INTERVAL '{bucket_seconds} seconds', schedule_interval => INTERVAL '{bucket_seconds} seconds');"
+            ),
+            format!(
+                "SELECT add_retention_policy('{staging}', INTERVAL '{} seconds');",
+                retention.as_secs()
+            ),
+        ];
+        if existing_columns.is_empty() {
+            log::info!("building continuous aggregate {view}");
+            statements.push(format!(
+                "ALTER MATERIALIZED VIEW {staging} RENAME TO {view_name};"
+            ));
+        } else {
+            let archive = (1..)
+                .map(|n| archived_name(&view, n))
+                .find(|archive| !aggregates.contains(archive))
+                .expect("some number is free");
+            let (archive_name, _) = view_names(&archive);
+            log::info!("replacing continuous aggregate {view}, keeping its history as {archive}");
+            // The old view keeps what it has, but only what it has: its retention empties it over time.
+            statements.push(format!(
+                "SELECT remove_continuous_aggregate_policy('{view}', if_exists => true); ALTER MATERIALIZED VIEW {view} SET (timescaledb.materialized_only = true); ALTER MATERIALIZED VIEW {view} RENAME TO {archive_name}; ALTER MATERIALIZED VIEW {staging} RENAME TO {view_name};"
+            ));
+            aggregates.insert(archive);
+        }
+
+        for statement in statements {
+            if let Err(e) = client.batch_execute(&statement).await {
+                log::error!("continuous aggregate {view} failed at `{statement}`: {e:?}");
+                built = false;
+                break;
+            }
+        }
+    }
+    built
+}
+
+/// Drops the view's archives that their retention has emptied
+async fn drop_emptied_archives(client: &Client, view: &str, aggregates: &mut BTreeSet<String>) {
+    let archives: Vec<String> = aggregates
+        .iter()
+        .filter(|aggregate| (1..=aggregates.len()).any(|n| archived_name(view, n) == **aggregate))
+        .cloned()
+        .collect();
+    for archive in archives {
+        let emptied = match client
+            .query_one(&format!("SELECT NOT EXISTS (SELECT 1 FROM {archive})"), &[])
+            .await
+        {
+            Ok(row) => row.get::<_, bool>(0),
+            Err(e) => {
+                log::warn!("could not check continuous aggregate {archive}: {e:?}");
+                continue;
+            }
+        };
+        if !emptied {
+            continue;
+        }
+        log::info!("dropping {archive}, its rollups are past retention");
+        match client
+            .batch_execute(&format!("DROP MATERIALIZED VIEW IF EXISTS {archive};"))
+            .await
+        {
+            Ok(()) => {
+                aggregates.remove(&archive);
+            }
+            Err(e) => log::warn!("could not drop continuous aggregate {archive}: {e:?}"),
+        }
+    }
+}
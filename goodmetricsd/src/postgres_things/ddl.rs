@@ -1,14 +1,81 @@
-use std::time::Duration;
+use std::collections::BTreeMap;
 
 use lazy_static::lazy_static;
 use regex::Regex;
 use tokio_postgres::Client;
 
+use super::table_policy::TablePolicy;
+
 lazy_static! {
     static ref NOT_WHITESPACE: Regex = Regex::new(r"[^\w]+").expect("regex compiles");
 }
 
-pub async fn add_column(
+/// Runs the statements as one simple query, which postgres treats as a single transaction.
+pub async fn in_transaction(
+    client: &Client,
+    statements: &[String],
+) -> Result<(), tokio_postgres::Error> {
+    client.batch_execute(&statements.join("\n")).await
+}
+
+pub fn add_column(table_name: &str, column_name: &str, data_type: &str) -> String {
+    format!(
+        "alter table {table} add column {column} {data_type};",
+        table = table_name,
+        column = column_name,
+        data_type = data_type,
+    )
+}
+
+/// [<schema>.]<prefix><metric>. Without a schema, tables go wherever the search_path says.
+pub fn table_name(schema: Option<&str>, prefix: &str, metric: &str) -> String {
+    let table = postgres_id(&format!("{prefix}{metric}"));
+    match schema {
+        Some(schema) => format!("{}.{table}", postgres_id(schema)),
+        None => table,
+    }
+}
+
+/// Whether a tenant can have this schema: a plain identifier that postgres and timescale don't use themselves.
+pub fn is_tenant_schema(schema: &str) -> bool {
+    let schema = postgres_id(schema);
+    !schema.is_empty()
+        && !schema.starts_with(|c: char| c.is_ascii_digit())
+        && !schema.starts_with("pg_")
+        && !schema.starts_with("_timescaledb")
+        && !schema.starts_with("timescaledb")
+        && !matches!(schema.as_str(), "public" | "information_schema")
+}
+
+pub fn create_schema(table_name: &str) -> Option<String> {
+    table_name
+        .split_once('.')
+        .map(|(schema, _)| format!("CREATE SCHEMA IF NOT EXISTS {schema};"))
+}
+
+/// column name -> type name, with domains like histogram by their own name
+pub async fn column_types(
+    client: &Client,
+    table_name: &str,
+) -> Result<BTreeMap<String, String>, tokio_postgres::Error> {
+    let rows = match table_name.split_once('.') {
+        Some((schema, table)) => client
+            .query(
+                "select column_name::text, coalesce(domain_name, udt_name)::text from information_schema.columns where table_schema = $1 and table_name = $2",
+                &[&schema, &table],
+            )
+            .await?,
+        None => client
+            .query(
+                "select column_name::text, coalesce(domain_name, udt_name)::text from information_schema.columns where table_schema = current_schema() and table_name = $1",
+                &[&table_name],
+            )
+            .await?,
+    };
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+pub async fn alter_column_type(
     client: &Client,
     table_name: &str,
     column_name: &str,
@@ -16,41 +83,161 @@ pub async fn add_column(
 ) -> Result<(), tokio_postgres::Error> {
     client
         .batch_execute(&format!(
-            "alter table {table} add column {column} {data_type}",
-            table = table_name,
-            column = column_name,
-            data_type = data_type,
+            "alter table {table_name} alter column {column_name} type {data_type} using {column_name}::{data_type}"
         ))
         .await
 }
 
-pub async fn create_table(
-    transaction: &Client,
+pub async fn rename_column(
+    client: &Client,
     table_name: &str,
-    retention: &Duration,
-    compress: bool,
+    column_name: &str,
+    new_column_name: &str,
 ) -> Result<(), tokio_postgres::Error> {
-    let chunk = "4h";
-    let compression_statement = if compress {
+    client
+        .batch_execute(&format!(
+            "alter table {table_name} rename column {column_name} to {new_column_name}"
+        ))
+        .await
+}
+
+/// The hypertable and its retention. Compression and space partitioning need their columns, so they come
+/// after the columns are added, in `table_settings`.
+pub fn create_table(table_name: &str, policy: &TablePolicy) -> String {
+    format!(
+        r#"CREATE TABLE {table_name} (time timestamptz);
+        SELECT * from create_hypertable('{table_name}', 'time', chunk_time_interval => INTERVAL '{chunk_seconds} seconds' );
+        SELECT add_retention_policy('{table_name}', INTERVAL '{retention_seconds} seconds');
+        "#,
+        chunk_seconds = policy.chunk_interval.as_secs(),
+        retention_seconds = policy.retention.as_secs(),
+    )
+}
+
+/// Space partitioning and compression for a new, still empty table
+pub fn table_settings(
+    table_name: &str,
+    policy: &TablePolicy,
+    columns: &BTreeMap<String, String>,
+) -> String {
+    let partition_statement = match &policy.space_partition {
+        Some(partition) if columns.contains_key(&partition.column) => format!(
+            "SELECT add_dimension('{table_name}', '{column}', number_partitions => {partitions});",
+            column = partition.column,
+            partitions = partition.partitions,
+        ),
+        Some(partition) => {
+            log::warn!(
+                "not partitioning {table_name} by {} because the first batch doesn't have it",
+                partition.column
+            );
+            "".to_string()
+        }
+        None => "".to_string(),
+    };
+    if !policy.compress {
+        return partition_statement;
+    }
+    format!(
+        "{partition_statement}\n{settings}\n{compression_policy}",
+        settings = compression_settings(table_name, policy, columns),
+        compression_policy = compression_policy(table_name, policy),
+    )
+}
+
+/// Brings an existing table's chunk interval, retention and compression in line with its policy.
+/// Each statement is separate so one that can't apply, like compression settings on a table with
+/// compressed chunks, doesn't stop the rest. Policies are swapped in one statement, which postgres
+/// runs as one transaction, so a policy is only removed when its replacement is added.
+pub fn reconcile_table_policy(
+    table_name: &str,
+    policy: &TablePolicy,
+    columns: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let mut statements = vec![
         format!(
-            r#"
-            ALTER TABLE {table_name} SET (timescaledb.compress, timescaledb.compress_orderby = 'time DESC', timescaledb.compress_chunk_time_interval = '24 hours');
-            SELECT add_compression_policy('{table_name}', INTERVAL '{chunk}');
-            "#
-        )
+            "SELECT set_chunk_time_interval('{table_name}', INTERVAL '{} seconds');",
+            policy.chunk_interval.as_secs()
+        ),
+        format!(
+            "SELECT remove_retention_policy('{table_name}', if_exists => true); SELECT add_retention_policy('{table_name}', INTERVAL '{} seconds');",
+            policy.retention.as_secs()
+        ),
+    ];
+    if policy.compress {
+        statements.push(compression_settings(table_name, policy, columns));
+        statements.push(format!(
+            "SELECT remove_compression_policy('{table_name}', if_exists => true); {}",
+            compression_policy(table_name, policy)
+        ));
     } else {
+        statements.push(format!(
+            "SELECT remove_compression_policy('{table_name}', if_exists => true);"
+        ));
+    }
+    statements
+}
+
+fn compression_settings(
+    table_name: &str,
+    policy: &TablePolicy,
+    columns: &BTreeMap<String, String>,
+) -> String {
+    let segmentby: Vec<&str> = policy
+        .compress_segmentby
+        .iter()
+        .filter(|column| columns.contains_key(*column))
+        .map(String::as_str)
+        .collect();
+    let segmentby_setting = if segmentby.is_empty() {
         "".to_string()
+    } else {
+        format!(
+            ", timescaledb.compress_segmentby = '{}'",
+            segmentby.join(",")
+        )
     };
-    transaction.batch_execute(
-    &format!(
-            r#"CREATE TABLE {table_name} (time timestamptz);
-            SELECT * from create_hypertable('{table_name}', 'time', chunk_time_interval => INTERVAL '{chunk}' );
-            SELECT add_retention_policy('{table_name}', INTERVAL '{retention_seconds} seconds');
-            {compression_statement}
-            "#,
-            retention_seconds=retention.as_secs(),
+    // Compressed chunks roll up to about a day, and have to be a multiple of the chunk interval.
+    let chunk_seconds = policy.chunk_interval.as_secs().max(1);
+    let rollup_setting = if chunk_seconds < DAY_SECONDS {
+        format!(
+            ", timescaledb.compress_chunk_time_interval = '{} seconds'",
+            DAY_SECONDS.div_ceil(chunk_seconds) * chunk_seconds
         )
-    ).await
+    } else {
+        "".to_string()
+    };
+    format!(
+        "ALTER TABLE {table_name} SET (timescaledb.compress, timescaledb.compress_orderby = 'time DESC'{segmentby_setting}{rollup_setting});"
+    )
+}
+
+fn compression_policy(table_name: &str, policy: &TablePolicy) -> String {
+    format!(
+        "SELECT add_compression_policy('{table_name}', INTERVAL '{} seconds');",
+        policy.compress_after.as_secs()
+    )
+}
+
+const DAY_SECONDS: u64 = 24 * 60 * 60;
+
+/// Postgres cuts identifiers down to this many bytes
+const MAX_IDENTIFIER_BYTES: usize = 63;
+
+/// A cleaned identifier, cut down the way postgres would so lookups by name find it.
+pub fn postgres_id(s: &str) -> String {
+    with_suffix(&clean_id(s), "")
+}
+
+/// Appends a suffix to an identifier, cutting the identifier rather than the suffix to fit.
+pub fn with_suffix(id: &str, suffix: &str) -> String {
+    let mut end = MAX_IDENTIFIER_BYTES
+        .saturating_sub(suffix.len())
+        .min(id.len());
+    while !id.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{suffix}", &id[..end])
 }
 
 pub fn clean_id(s: &str) -> String {
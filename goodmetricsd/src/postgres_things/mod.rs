@@ -1,6 +1,9 @@
+pub mod continuous_aggregate;
 pub mod ddl;
 pub mod histogram;
 pub mod postgres_connector;
 pub mod statistic_set;
+pub mod table_policy;
+pub mod table_schema;
 pub mod tdigest;
 pub mod type_conversion;
@@ -1,22 +1,97 @@
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::SystemTime};
+
 use bb8::Pool;
 use bb8_postgres::PostgresConnectionManager;
-use tokio_postgres::NoTls;
+use clap::ValueEnum;
+use communication::{parse_client_certificate, ClientCertificate};
+use serde_derive::Deserialize;
+use tokio_postgres::config::SslMode;
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tokio_rustls::rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, CertificateError, ClientConfig, Error, RootCertStore, ServerName,
+};
 
 use crate::sink::sink_error::{SinkError, StringError};
 
+/// Plaintext connections just never upgrade, so every connection goes through rustls.
+pub type ConnectionManager = PostgresConnectionManager<MakeRustlsConnect>;
+
+/// libpq's sslmodes. tokio-postgres only understands disable, prefer and require in a connection string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum PostgresSslMode {
+    /// Never encrypt
+    Disable,
+    /// Encrypt if the server supports it, without verifying its certificate
+    Prefer,
+    /// Always encrypt, without verifying the server's certificate
+    Require,
+    /// Always encrypt, and verify the server's certificate chains to a root certificate
+    VerifyCa,
+    /// verify-ca, and also verify the certificate is for the host in the connection string
+    VerifyFull,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PostgresTls {
+    /// When unset, the connection string's sslmode is used without verification, like libpq.
+    pub ssl_mode: Option<PostgresSslMode>,
+    /// pem root certificates for verify-ca and verify-full. When unset, the platform's roots are used.
+    pub root_certificate_pem: Option<Vec<u8>>,
+    pub client_certificate: Option<ClientCertificate>,
+}
+
 pub struct PostgresConnector {
-    pool: Pool<PostgresConnectionManager<NoTls>>,
+    pool: Pool<ConnectionManager>,
 }
 
 impl PostgresConnector {
     pub async fn new(
         connection_string: String,
         max_conns: usize,
+        tls: PostgresTls,
     ) -> Result<PostgresConnector, SinkError> {
-        let pg_manager = PostgresConnectionManager::new_from_stringlike(
-            connection_string,
-            tokio_postgres::NoTls,
-        )?;
+        // tokio-postgres rejects verify-ca and verify-full, so goodmetricsd handles the ssl settings itself.
+        let (connection_string, ssl_settings) = split_ssl_settings(&connection_string);
+        let mut config = tokio_postgres::Config::from_str(&connection_string)?;
+        let ssl_mode = match tls.ssl_mode {
+            Some(ssl_mode) => ssl_mode,
+            None => match ssl_settings.get("sslmode").map(String::as_str) {
+                None | Some("prefer") | Some("allow") => PostgresSslMode::Prefer,
+                Some("disable") => PostgresSslMode::Disable,
+                Some("require") => PostgresSslMode::Require,
+                Some("verify-ca") => PostgresSslMode::VerifyCa,
+                Some("verify-full") => PostgresSslMode::VerifyFull,
+                Some(other) => {
+                    return Err(SinkError::StringError(StringError {
+                        message: format!("unknown postgres sslmode {other}"),
+                    }))
+                }
+            },
+        };
+        let mut tls = tls;
+        if tls.root_certificate_pem.is_none() {
+            // libpq's sslrootcert=system means the platform's roots, which is the default here.
+            if let Some(path) = ssl_settings
+                .get("sslrootcert")
+                .filter(|path| path.as_str() != "system")
+            {
+                tls.root_certificate_pem = Some(tokio::fs::read(path).await.map_err(|e| {
+                    SinkError::other("could not read postgres sslrootcert", Box::new(e))
+                })?);
+            }
+        }
+        config.ssl_mode(match ssl_mode {
+            PostgresSslMode::Disable => SslMode::Disable,
+            PostgresSslMode::Prefer => SslMode::Prefer,
+            PostgresSslMode::Require | PostgresSslMode::VerifyCa | PostgresSslMode::VerifyFull => {
+                SslMode::Require
+            }
+        });
+        log::info!("connecting to postgres with sslmode {ssl_mode:?}");
+
+        let pg_manager = PostgresConnectionManager::new(config, rustls_connector(ssl_mode, &tls)?);
         let pool = match Pool::builder()
             .max_size(max_conns as u32)
             .build(pg_manager)
@@ -31,7 +106,7 @@ impl PostgresConnector {
 
     pub async fn use_connection(
         &self,
-    ) -> Result<bb8::PooledConnection<'_, PostgresConnectionManager<NoTls>>, SinkError> {
+    ) -> Result<bb8::PooledConnection<'_, ConnectionManager>, SinkError> {
         // need to get the connection via the method that ensures it's connected
         let poolconn = match self.pool.get().await {
             Ok(client) => client,
@@ -44,3 +119,249 @@ impl PostgresConnector {
         Ok(poolconn)
     }
 }
+
+fn rustls_connector(
+    ssl_mode: PostgresSslMode,
+    tls: &PostgresTls,
+) -> Result<MakeRustlsConnect, SinkError> {
+    let verify = matches!(
+        ssl_mode,
+        PostgresSslMode::VerifyCa | PostgresSslMode::VerifyFull
+    );
+    let mut roots = RootCertStore::empty();
+    let root_certificates: Vec<Vec<u8>> = match &tls.root_certificate_pem {
+        Some(pem) => rustls_pemfile::certs(&mut pem.as_slice()).map_err(|e| {
+            SinkError::other("could not parse postgres root certificate", Box::new(e))
+        })?,
+        // Unverified modes don't look at roots, so they don't need the platform's to load.
+        None if verify => rustls_native_certs::load_native_certs()
+            .map_err(|e| {
+                SinkError::other("could not load platform root certificates", Box::new(e))
+            })?
+            .into_iter()
+            .map(|certificate| certificate.0)
+            .collect(),
+        None => Vec::new(),
+    };
+    roots.add_parsable_certificates(&root_certificates);
+    if roots.is_empty() && verify {
+        return Err(SinkError::StringError(StringError {
+            message: format!("no root certificates to verify postgres with for {ssl_mode:?}"),
+        }));
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots.clone());
+    let mut config = match &tls.client_certificate {
+        Some(client_certificate) => {
+            let (chain, key) = parse_client_certificate(client_certificate)
+                .map_err(|e| SinkError::other("could not parse postgres client certificate", e))?;
+            builder
+                .with_client_auth_cert(chain, key)
+                .map_err(|e| SinkError::other("invalid postgres client certificate", Box::new(e)))?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    match ssl_mode {
+        PostgresSslMode::Disable | PostgresSslMode::Prefer | PostgresSslMode::Require => config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(UnverifiedServer {})),
+        PostgresSslMode::VerifyCa => {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(AnyServerName {
+                    inner: WebPkiVerifier::new(roots, None),
+                }))
+        }
+        PostgresSslMode::VerifyFull => {}
+    }
+
+    Ok(MakeRustlsConnect::new(config))
+}
+
+/// Takes sslmode and sslrootcert out of a key=value or postgres:// connection string.
+fn split_ssl_settings(connection_string: &str) -> (String, HashMap<String, String>) {
+    let mut ssl_settings = HashMap::new();
+    let mut is_ssl_setting = |key: &str, value: &str| {
+        let is_ssl = key == "sslmode" || key == "sslrootcert";
+        if is_ssl {
+            ssl_settings.insert(key.to_string(), value.to_string());
+        }
+        is_ssl
+    };
+
+    let trimmed = connection_string.trim();
+    if trimmed.starts_with("postgres://") || trimmed.starts_with("postgresql://") {
+        let remaining = match trimmed.split_once('?') {
+            Some((base, query)) => {
+                let query: Vec<&str> = query
+                    .split('&')
+                    .filter(|parameter| {
+                        let (key, value) = parameter.split_once('=').unwrap_or((*parameter, ""));
+                        !is_ssl_setting(key, value)
+                    })
+                    .collect();
+                if query.is_empty() {
+                    base.to_string()
+                } else {
+                    format!("{base}?{}", query.join("&"))
+                }
+            }
+            None => trimmed.to_string(),
+        };
+        return (remaining, ssl_settings);
+    }
+
+    // key = value pairs, where a value may be 'single quoted' with \ escapes.
+    let mut remaining = Vec::new();
+    let mut chars = trimmed.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| *c != '=' && !c.is_whitespace()) {
+            key.push(c);
+        }
+        if key.is_empty() {
+            break;
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        chars.next_if_eq(&'=');
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut raw_value = String::new();
+        let mut value = String::new();
+        if chars.next_if_eq(&'\'').is_some() {
+            raw_value.push('\'');
+            while let Some(c) = chars.next() {
+                raw_value.push(c);
+                match c {
+                    '\\' => {
+                        if let Some(escaped) = chars.next() {
+                            raw_value.push(escaped);
+                            value.push(escaped);
+                        }
+                    }
+                    '\'' => break,
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                raw_value.push(c);
+                value.push(c);
+            }
+        }
+        if !is_ssl_setting(&key, &value) {
+            remaining.push(format!("{key}={raw_value}"));
+        }
+    }
+    (remaining.join(" "), ssl_settings)
+}
+
+/// Encryption without authentication, like libpq's require.
+struct UnverifiedServer {}
+
+impl ServerCertVerifier for UnverifiedServer {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Verifies the chain but not the host name, like libpq's verify-ca.
+struct AnyServerName {
+    inner: WebPkiVerifier,
+}
+
+impl ServerCertVerifier for AnyServerName {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        match self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        ) {
+            Err(Error::InvalidCertificate(CertificateError::NotValidForName)) => {
+                Ok(ServerCertVerified::assertion())
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_ssl_settings;
+
+    #[test]
+    fn key_value_settings_are_taken_out() {
+        let (remaining, ssl) = split_ssl_settings(
+            "host=localhost sslmode=verify-full port=5432 sslrootcert=/etc/ca.pem user=me",
+        );
+
+        assert_eq!(remaining, "host=localhost port=5432 user=me");
+        assert_eq!(ssl["sslmode"], "verify-full");
+        assert_eq!(ssl["sslrootcert"], "/etc/ca.pem");
+    }
+
+    #[test]
+    fn quoted_values_keep_their_quotes_and_escapes() {
+        let (remaining, ssl) = split_ssl_settings(
+            r"password='it\'s a secret' sslrootcert = '/etc/my certs/ca.pem' dbname=metrics",
+        );
+
+        assert_eq!(remaining, r"password='it\'s a secret' dbname=metrics");
+        assert_eq!(ssl["sslrootcert"], "/etc/my certs/ca.pem");
+    }
+
+    #[test]
+    fn url_settings_are_taken_out_of_the_query() {
+        let (remaining, ssl) = split_ssl_settings(
+            "postgres://me@localhost:5432/metrics?sslmode=require&application_name=goodmetrics",
+        );
+
+        assert_eq!(
+            remaining,
+            "postgres://me@localhost:5432/metrics?application_name=goodmetrics"
+        );
+        assert_eq!(ssl["sslmode"], "require");
+    }
+
+    #[test]
+    fn url_without_other_parameters_loses_its_query() {
+        let (remaining, ssl) =
+            split_ssl_settings("postgresql://localhost/metrics?sslmode=disable&sslrootcert=ca.pem");
+
+        assert_eq!(remaining, "postgresql://localhost/metrics");
+        assert_eq!(ssl["sslmode"], "disable");
+        assert_eq!(ssl["sslrootcert"], "ca.pem");
+    }
+
+    #[test]
+    fn strings_without_ssl_settings_are_unchanged() {
+        let (remaining, ssl) = split_ssl_settings("host=localhost user=me");
+        assert_eq!(remaining, "host=localhost user=me");
+        assert!(ssl.is_empty());
+
+        let (remaining, ssl) = split_ssl_settings("postgres://localhost/metrics");
+        assert_eq!(remaining, "postgres://localhost/metrics");
+        assert!(ssl.is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use serde_derive::Deserialize;
+
+use crate::config::options::Options;
+
+/// Overrides the global table settings for matching metrics. Unset settings keep the global ones.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TablePolicyRule {
+    /// A metric name, or a prefix ending in `*`.
+    pub metric: String,
+    pub chunk_interval: Option<Duration>,
+    pub retention: Option<Duration>,
+    pub compress: Option<bool>,
+    pub compress_after: Option<Duration>,
+    pub compress_segmentby: Option<Vec<String>>,
+    pub space_partition: Option<SpacePartition>,
+}
+
+impl TablePolicyRule {
+    fn matches(&self, metric: &str) -> bool {
+        match self.metric.strip_suffix('*') {
+            Some(prefix) => metric.starts_with(prefix),
+            None => self.metric == metric,
+        }
+    }
+}
+
+/// Hash partitions on a dimension column, in addition to time
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct SpacePartition {
+    pub column: String,
+    pub partitions: u32,
+}
+
+/// <metric>:<setting>=<value>[;<setting>=<value>...], like
+/// api_*:chunk=30m;retention=14d;compress=true;compress_after=2h;segmentby=host+region;partition=host/4
+pub fn parse_table_policy_rule(value: &str) -> Result<TablePolicyRule, String> {
+    let (metric, settings) = value
+        .split_once(':')
+        .ok_or_else(|| format!("table policy needs <metric>:<setting>=<value>: {value}"))?;
+    let mut rule = TablePolicyRule {
+        metric: metric.to_string(),
+        ..Default::default()
+    };
+    for setting in settings.split(';').filter(|s| !s.is_empty()) {
+        let (name, setting_value) = setting
+            .split_once('=')
+            .ok_or_else(|| format!("table policy setting needs <setting>=<value>: {setting}"))?;
+        let duration = || {
+            humantime::parse_duration(setting_value)
+                .map_err(|e| format!("table policy {name} is not a duration: {e}"))
+        };
+        match name {
+            "chunk" => rule.chunk_interval = Some(duration()?),
+            "retention" => rule.retention = Some(duration()?),
+            "compress" => {
+                rule.compress = Some(
+                    setting_value
+                        .parse()
+                        .map_err(|e| format!("table policy compress is not a bool: {e}"))?,
+                )
+            }
+            "compress_after" => rule.compress_after = Some(duration()?),
+            "segmentby" => {
+                rule.compress_segmentby =
+                    Some(setting_value.split('+').map(str::to_string).collect())
+            }
+            "partition" => {
+                let (column, partitions) = setting_value.split_once('/').ok_or_else(|| {
+                    format!("table policy partition needs <column>/<partitions>: {setting_value}")
+                })?;
+                rule.space_partition = Some(SpacePartition {
+                    column: column.to_string(),
+                    partitions: partitions
+                        .parse()
+                        .map_err(|e| format!("table policy partitions is not a number: {e}"))?,
+                });
+            }
+            _ => return Err(format!("unknown table policy setting: {name}")),
+        }
+    }
+    Ok(rule)
+}
+
+/// The settings one table gets
+#[derive(Debug, Clone)]
+pub struct TablePolicy {
+    pub chunk_interval: Duration,
+    pub retention: Duration,
+    pub compress: bool,
+    pub compress_after: Duration,
+    pub compress_segmentby: Vec<String>,
+    pub space_partition: Option<SpacePartition>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TablePolicies {
+    default: TablePolicy,
+    rules: Vec<TablePolicyRule>,
+}
+
+impl TablePolicies {
+    pub fn new(options: &Options) -> Self {
+        Self {
+            default: TablePolicy {
+                chunk_interval: options.chunk_interval,
+                retention: options.default_retention,
+                compress: options.compress_new_tables,
+                compress_after: options.compress_after,
+                compress_segmentby: Vec::new(),
+                space_partition: None,
+            },
+            rules: options.table_policy.clone(),
+        }
+    }
+
+    /// The first matching rule over the global settings
+    pub fn for_metric(&self, metric: &str) -> TablePolicy {
+        let mut policy = self.default.clone();
+        if let Some(rule) = self.rules.iter().find(|rule| rule.matches(metric)) {
+            let rule = rule.clone();
+            policy.chunk_interval = rule.chunk_interval.unwrap_or(policy.chunk_interval);
+            policy.retention = rule.retention.unwrap_or(policy.retention);
+            policy.compress = rule.compress.unwrap_or(policy.compress);
+            policy.compress_after = rule.compress_after.unwrap_or(policy.compress_after);
+            policy.compress_segmentby =
+                rule.compress_segmentby.unwrap_or(policy.compress_segmentby);
+            policy.space_partition = rule.space_partition.or(policy.space_partition);
+        }
+        policy
+    }
+}
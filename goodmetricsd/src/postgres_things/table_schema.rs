@@ -0,0 +1,57 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+};
+
+use tokio_postgres::Client;
+
+use super::ddl;
+
+/// Each table's columns and their types, loaded from information_schema the first time a table is used
+/// and again after anything changes it. A table with no columns doesn't exist.
+#[derive(Default)]
+pub struct TableSchemas {
+    tables: RefCell<HashMap<String, BTreeMap<String, String>>>,
+    reconciled: RefCell<HashSet<String>>,
+    /// Tables whose continuous aggregates all exist
+    aggregated: RefCell<HashSet<String>>,
+}
+
+impl TableSchemas {
+    pub async fn columns(
+        &self,
+        client: &Client,
+        table: &str,
+    ) -> Result<BTreeMap<String, String>, tokio_postgres::Error> {
+        if let Some(columns) = self.tables.borrow().get(table) {
+            return Ok(columns.clone());
+        }
+        let columns = ddl::column_types(client, table).await?;
+        self.tables
+            .borrow_mut()
+            .insert(table.to_string(), columns.clone());
+        Ok(columns)
+    }
+
+    pub fn forget(&self, table: &str) {
+        self.tables.borrow_mut().remove(table);
+    }
+
+    pub fn has_continuous_aggregates(&self, table: &str) -> bool {
+        self.aggregated.borrow().contains(table)
+    }
+
+    /// Whether the table's continuous aggregates all exist, or need another try
+    pub fn continuous_aggregates_built(&self, table: &str, built: bool) {
+        if built {
+            self.aggregated.borrow_mut().insert(table.to_string());
+        } else {
+            self.aggregated.borrow_mut().remove(table);
+        }
+    }
+
+    /// True the first time it's asked about a table
+    pub fn first_reconcile(&self, table: &str) -> bool {
+        self.reconciled.borrow_mut().insert(table.to_string())
+    }
+}
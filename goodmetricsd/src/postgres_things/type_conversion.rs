@@ -1,6 +1,6 @@
 use std::collections::BTreeMap;
 
-use postgres_types::Type;
+use postgres_types::{Kind, Type};
 
 use communication::proto::goodmetrics::{dimension, measurement, Datum, Dimension, Measurement};
 
@@ -12,6 +12,25 @@ pub struct TypeConverter {
 }
 
 impl TypeConverter {
+    /// For sinks that want goodmetrics' wide schema without a postgres to ask for the special types.
+    pub fn without_database() -> Self {
+        Self {
+            statistic_set_type: Type::new(
+                "statistic_set".to_string(),
+                0,
+                Kind::Composite(vec![]),
+                "public".to_string(),
+            ),
+            histogram_type: Type::new(
+                "histogram".to_string(),
+                0,
+                Kind::Domain(Type::JSONB),
+                "public".to_string(),
+            ),
+            tdigest_type: Type::new("tdigest".to_string(), 0, Kind::Simple, "public".to_string()),
+        }
+    }
+
     pub fn measurement_sql_type(&self, measurement: &Measurement) -> Option<Type> {
         measurement.value.as_ref().map(|v| match v {
             measurement::Value::I64(_) => Type::INT8,
@@ -1,5 +1,11 @@
+use std::sync::Arc;
+
 use tonic::Response;
 
+use crate::ingest::ingest_stats::{now_nanos, IngestStats};
+use crate::ingest::sampling::SamplingPolicy;
+use crate::ingest::tenant::TenantPolicy;
+use crate::ingest::timestamp_policy::TimestampPolicy;
 use crate::sink::metricssendqueue::MetricsSendQueue;
 use crate::sink::MetricsSink;
 use communication::proto::goodmetrics::metrics_server::Metrics;
@@ -8,6 +14,10 @@ use communication::proto::goodmetrics::{MetricsReply, MetricsRequest};
 #[derive(Debug)]
 pub struct GoodmetricsServer {
     pub metrics_sink: MetricsSendQueue,
+    pub timestamp_policy: Arc<TimestampPolicy>,
+    pub sampling_policy: Arc<SamplingPolicy>,
+    pub tenant_policy: Arc<TenantPolicy>,
+    pub ingest_stats: Arc<IngestStats>,
 }
 
 #[tonic::async_trait]
@@ -18,12 +28,26 @@ impl Metrics for GoodmetricsServer {
     ) -> Result<tonic::Response<MetricsReply>, tonic::Status> {
         log::trace!("request: {:?}", request);
 
+        let api_key = request
+            .metadata()
+            .get("authorization")
+            .and_then(|authorization| authorization.to_str().ok())
+            .map(str::to_string);
         // We shared the dimensions across the wire, but here we'll keep it simple and just spew it all across each datum
         let mut request = request.into_inner();
         request
             .metrics
             .iter_mut()
             .for_each(|datum| datum.dimensions.extend(request.shared_dimensions.clone()));
+        self.tenant_policy
+            .apply(api_key.as_deref(), &mut request.metrics);
+        self.timestamp_policy
+            .apply(&mut request.metrics, now_nanos(), &self.ingest_stats);
+        self.sampling_policy
+            .apply(&mut request.metrics, &self.ingest_stats);
+        if request.metrics.is_empty() {
+            return Ok(Response::new(MetricsReply {}));
+        }
         let queue_result = self.metrics_sink.drain(request.metrics);
 
         match queue_result {
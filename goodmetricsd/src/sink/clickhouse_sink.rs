@@ -0,0 +1,310 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use communication::proto::goodmetrics::{dimension, measurement, Datum, Dimension, Measurement};
+use serde_json::{json, Map, Value};
+use tokio::time::{timeout_at, Instant};
+
+use crate::{config::options::Options, postgres_things::ddl::clean_id};
+
+use super::{
+    group_metrics,
+    metricssendqueue::MetricsReceiveQueue,
+    sink_error::{SinkError, StringError},
+};
+
+#[derive(Debug, Clone)]
+struct ClickhouseConfig {
+    pub endpoint: String,
+    pub database: String,
+    pub user: Option<String>,
+    pub password: Option<String>,
+}
+
+/// Mirrors the postgres sink for clickhouse's http interface: a MergeTree table per metric,
+/// created on first sight, with a column added for each new dimension and measurement.
+pub struct ClickhouseSender {
+    rx: MetricsReceiveQueue,
+    client: reqwest::Client,
+    configuration: ClickhouseConfig,
+    /// table -> column -> clickhouse type, as last read from system.columns
+    known_columns: HashMap<String, BTreeMap<String, String>>,
+}
+
+impl ClickhouseSender {
+    pub fn new(rx: MetricsReceiveQueue, options: Options) -> Result<ClickhouseSender, SinkError> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(60))
+            .build()
+            .map_err(|e| SinkError::other("could not build clickhouse client", Box::new(e)))?;
+
+        Ok(ClickhouseSender {
+            rx,
+            client,
+            configuration: ClickhouseConfig {
+                endpoint: options.clickhouse_endpoint.unwrap_or_default(),
+                database: options.clickhouse_database,
+                user: options.clickhouse_user,
+                password: options.clickhouse_password,
+            },
+            known_columns: HashMap::new(),
+        })
+    }
+
+    pub async fn consume_stuff(mut self) -> Result<u32, SinkError> {
+        log::info!(
+            "started clickhouse consumer for {}",
+            self.configuration.endpoint
+        );
+        self.query(&format!(
+            "CREATE DATABASE IF NOT EXISTS `{}`",
+            self.configuration.database
+        ))
+        .await?;
+
+        while let Some(mut batch) = self.rx.recv().await {
+            log::info!("Sender woke. Trying to collect a batch...");
+
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let mut api_calls: u32 = 1;
+            while let Ok(Some(mut extras)) = timeout_at(deadline, self.rx.recv()).await {
+                api_calls += 1;
+                batch.append(&mut extras);
+            }
+
+            let batchlen = batch.len();
+            let grouped_metrics = group_metrics(batch);
+            log::info!(
+                "Sending some metrics. batch size: {}, metrics: {}, api calls: {}",
+                batchlen,
+                grouped_metrics.len(),
+                api_calls,
+            );
+            for (metric, datums) in grouped_metrics {
+                let table = clean_id(&metric);
+                if let Err(e) = self.send_some(&table, &datums).await {
+                    // The schema may have changed under us; look again before the next batch.
+                    self.known_columns.remove(&table);
+                    log::error!("dropping {} rows for {}: {:?}", datums.len(), table, e);
+                }
+            }
+        }
+
+        log::info!("ended clickhouse consumer");
+        Ok(1)
+    }
+
+    async fn send_some(&mut self, table: &str, datums: &[Datum]) -> Result<(), SinkError> {
+        self.ensure_columns(table, datums).await?;
+
+        let mut body = String::with_capacity(datums.len() * 256);
+        for datum in datums {
+            body.push_str(&to_json_row(datum).to_string());
+            body.push('\n');
+        }
+        self.query_with_body(
+            &format!(
+                "INSERT INTO `{}`.`{table}` FORMAT JSONEachRow",
+                self.configuration.database
+            ),
+            body,
+        )
+        .await?;
+        log::info!("committed rows: {}", datums.len());
+
+        Ok(())
+    }
+
+    /// Creates the table and adds the columns this batch needs, all before the insert.
+    async fn ensure_columns(&mut self, table: &str, datums: &[Datum]) -> Result<(), SinkError> {
+        if !self.known_columns.contains_key(table) {
+            self.query(&format!(
+                "CREATE TABLE IF NOT EXISTS `{}`.`{table}` (time DateTime64(9, 'UTC')) ENGINE = MergeTree PARTITION BY toDate(time) ORDER BY time",
+                self.configuration.database
+            ))
+            .await?;
+            let columns = self.load_columns(table).await?;
+            self.known_columns.insert(table.to_string(), columns);
+        }
+
+        let mut wanted: BTreeMap<String, &'static str> = BTreeMap::new();
+        for datum in datums {
+            for (name, dimension) in &datum.dimensions {
+                if let Some(t) = dimension_type(dimension) {
+                    wanted.insert(clean_id(name), t);
+                }
+            }
+            for (name, measurement) in &datum.measurements {
+                if let Some(t) = measurement_type(measurement) {
+                    wanted.insert(clean_id(name), t);
+                }
+            }
+        }
+
+        let known = self.known_columns.get(table).cloned().unwrap_or_default();
+        let missing: Vec<String> = wanted
+            .iter()
+            .filter(|(column, _)| !known.contains_key(*column))
+            .map(|(column, data_type)| format!("ADD COLUMN IF NOT EXISTS `{column}` {data_type}"))
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        log::info!("adding {} columns to {}", missing.len(), table);
+        self.query(&format!(
+            "ALTER TABLE `{}`.`{table}` {}",
+            self.configuration.database,
+            missing.join(", ")
+        ))
+        .await?;
+        let columns = self.load_columns(table).await?;
+        self.known_columns.insert(table.to_string(), columns);
+
+        Ok(())
+    }
+
+    async fn load_columns(&self, table: &str) -> Result<BTreeMap<String, String>, SinkError> {
+        let rows = self
+            .query(&format!(
+                "SELECT name, type FROM system.columns WHERE database = '{}' AND table = '{table}' FORMAT JSONEachRow",
+                self.configuration.database
+            ))
+            .await?;
+        Ok(rows
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .filter_map(|row| {
+                Some((
+                    row.get("name")?.as_str()?.to_string(),
+                    row.get("type")?.as_str()?.to_string(),
+                ))
+            })
+            .collect())
+    }
+
+    async fn query(&self, query: &str) -> Result<String, SinkError> {
+        self.query_with_body(query, String::new()).await
+    }
+
+    async fn query_with_body(&self, query: &str, body: String) -> Result<String, SinkError> {
+        let (query, body) = if body.is_empty() {
+            (None, query.to_string())
+        } else {
+            (Some(query), body)
+        };
+        let mut request = self
+            .client
+            .post(&self.configuration.endpoint)
+            .query(&[("date_time_input_format", "best_effort")])
+            .body(body);
+        if let Some(query) = query {
+            request = request.query(&[("query", query)]);
+        }
+        if let Some(user) = &self.configuration.user {
+            request = request.header("X-ClickHouse-User", user);
+        }
+        if let Some(password) = &self.configuration.password {
+            request = request.header("X-ClickHouse-Key", password);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| SinkError::other("clickhouse request failed", Box::new(e)))?;
+        let status = response.status();
+        let text = response
+            .text()
+            .await
+            .map_err(|e| SinkError::other("clickhouse response failed", Box::new(e)))?;
+        if status.is_success() {
+            Ok(text)
+        } else {
+            Err(SinkError::StringError(StringError {
+                message: format!("clickhouse responded {status}: {text}"),
+            }))
+        }
+    }
+}
+
+fn dimension_type(dimension: &Dimension) -> Option<&'static str> {
+    dimension.value.as_ref().map(|value| match value {
+        dimension::Value::String(_) => "Nullable(String)",
+        dimension::Value::Number(_) => "Nullable(UInt64)",
+        dimension::Value::Boolean(_) => "Nullable(Bool)",
+    })
+}
+
+// Tuples and Maps can't be Nullable in clickhouse; rows without them get the empty default.
+fn measurement_type(measurement: &Measurement) -> Option<&'static str> {
+    measurement.value.as_ref().map(|value| match value {
+        measurement::Value::I64(_) => "Nullable(Int64)",
+        measurement::Value::I32(_) => "Nullable(Int32)",
+        measurement::Value::F64(_) => "Nullable(Float64)",
+        measurement::Value::F32(_) => "Nullable(Float32)",
+        measurement::Value::StatisticSet(_) => {
+            "Tuple(minimum Float64, maximum Float64, samplesum Float64, samplecount UInt64)"
+        }
+        measurement::Value::Histogram(_) => "Map(Int64, UInt64)",
+        // Centroids roll up with quantileTDigestWeighted(q)(centroid.1, centroid.2) over arrayJoin(centroids)
+        measurement::Value::Tdigest(_) => {
+            "Tuple(count UInt64, sum Float64, min Float64, max Float64, centroids Array(Tuple(mean Float64, weight UInt64)))"
+        }
+    })
+}
+
+fn to_json_row(datum: &Datum) -> Value {
+    let mut row = Map::new();
+    row.insert(
+        "time".to_string(),
+        json!(
+            humantime::format_rfc3339_nanos(UNIX_EPOCH + Duration::from_nanos(datum.unix_nanos))
+                .to_string()
+        ),
+    );
+    for (name, dimension) in &datum.dimensions {
+        if let Some(value) = &dimension.value {
+            row.insert(
+                clean_id(name),
+                match value {
+                    dimension::Value::String(s) => json!(s),
+                    dimension::Value::Number(n) => json!(n),
+                    dimension::Value::Boolean(b) => json!(b),
+                },
+            );
+        }
+    }
+    for (name, measurement) in &datum.measurements {
+        if let Some(value) = &measurement.value {
+            row.insert(
+                clean_id(name),
+                match value {
+                    measurement::Value::I64(i) => json!(i),
+                    measurement::Value::I32(i) => json!(i),
+                    measurement::Value::F64(f) => json!(f),
+                    measurement::Value::F32(f) => json!(f),
+                    measurement::Value::StatisticSet(s) => {
+                        json!([s.minimum, s.maximum, s.samplesum, s.samplecount])
+                    }
+                    measurement::Value::Histogram(h) => json!(h
+                        .buckets
+                        .iter()
+                        .map(|(bucket, count)| (bucket.to_string(), *count))
+                        .collect::<BTreeMap<String, u64>>()),
+                    measurement::Value::Tdigest(t) => json!([
+                        t.count,
+                        t.sum,
+                        t.min,
+                        t.max,
+                        t.centroids
+                            .iter()
+                            .map(|c| json!([c.mean, c.weight]))
+                            .collect::<Vec<Value>>()
+                    ]),
+                },
+            );
+        }
+    }
+    Value::Object(row)
+}
@@ -0,0 +1,206 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use communication::proto::goodmetrics::{dimension, measurement, Datum};
+use serde_derive::Deserialize;
+use serde_json::json;
+use tokio::time::{timeout_at, Instant};
+
+use crate::{
+    config::options::Options,
+    postgres_things::{
+        ddl::clean_id, histogram::to_jsonmap, statistic_set::SqlStatisticSet, tdigest::SqlTdigest,
+        type_conversion::TypeConverter,
+    },
+};
+
+use super::{group_metrics, metricssendqueue::MetricsReceiveQueue, sink_error::SinkError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+pub enum DebugFormat {
+    /// A table per metric per batch, with the column types timescale would get.
+    Pretty,
+    /// A line of json per metric per batch, with the column types and the datums.
+    Json,
+}
+
+/// Prints what clients send, so you can see your instrumentation without a database.
+pub struct DebugSender {
+    rx: MetricsReceiveQueue,
+    format: DebugFormat,
+    metric_filters: Vec<String>,
+    type_converter: TypeConverter,
+}
+
+impl DebugSender {
+    pub fn new(rx: MetricsReceiveQueue, options: Options) -> DebugSender {
+        DebugSender {
+            rx,
+            format: options.debug_sink.unwrap_or(DebugFormat::Pretty),
+            metric_filters: options.debug_sink_metric,
+            type_converter: TypeConverter::without_database(),
+        }
+    }
+
+    pub async fn consume_stuff(mut self) -> Result<u32, SinkError> {
+        log::info!("started debug consumer");
+
+        while let Some(mut batch) = self.rx.recv().await {
+            let deadline = Instant::now() + Duration::from_secs(1);
+            while let Ok(Some(mut extras)) = timeout_at(deadline, self.rx.recv()).await {
+                batch.append(&mut extras);
+            }
+
+            let mut stdout = std::io::stdout().lock();
+            for (metric, datums) in group_metrics(batch) {
+                if !self.wants(&metric) {
+                    continue;
+                }
+                let printed = match self.format {
+                    DebugFormat::Pretty => writeln!(stdout, "{}", self.table(&metric, &datums)),
+                    DebugFormat::Json => writeln!(stdout, "{}", self.json(&metric, &datums)),
+                };
+                if let Err(e) = printed {
+                    log::error!("could not print debug output: {:?}", e);
+                }
+            }
+        }
+
+        log::info!("ended debug consumer");
+        Ok(1)
+    }
+
+    fn wants(&self, metric: &str) -> bool {
+        self.metric_filters.is_empty()
+            || self
+                .metric_filters
+                .iter()
+                .any(|filter| match filter.strip_suffix('*') {
+                    Some(prefix) => metric.starts_with(prefix),
+                    None => filter == metric,
+                })
+    }
+
+    // time, dimensions[], measurements[] like the postgres sink's copy
+    fn columns(&self, datums: &[Datum]) -> Vec<(String, String, String)> {
+        let dimension_types = self.type_converter.get_dimension_type_map(datums);
+        let measurement_types = self.type_converter.get_measurement_type_map(datums);
+
+        std::iter::once((
+            "time".to_string(),
+            "time".to_string(),
+            "timestamptz".to_string(),
+        ))
+        .chain(
+            dimension_types
+                .into_iter()
+                .map(|(name, t)| (clean_id(&name), name, t.name().to_string())),
+        )
+        .chain(
+            measurement_types
+                .into_iter()
+                .map(|(name, t)| (clean_id(&name), name, t.name().to_string())),
+        )
+        .collect()
+    }
+
+    fn json(&self, metric: &str, datums: &[Datum]) -> serde_json::Value {
+        let columns: BTreeMap<String, String> = self
+            .columns(datums)
+            .into_iter()
+            .map(|(column, _, data_type)| (column, data_type))
+            .collect();
+        json!({
+            "metric": metric,
+            "table": clean_id(metric),
+            "columns": columns,
+            "datums": datums,
+        })
+    }
+
+    fn table(&self, metric: &str, datums: &[Datum]) -> String {
+        let columns = self.columns(datums);
+        let header: Vec<String> = columns
+            .iter()
+            .map(|(column, _, data_type)| format!("{column} ({data_type})"))
+            .collect();
+        let rows: Vec<Vec<String>> = datums
+            .iter()
+            .map(|datum| {
+                columns
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (_, name, _))| {
+                        if i == 0 {
+                            return humantime::format_rfc3339_nanos(
+                                UNIX_EPOCH + Duration::from_nanos(datum.unix_nanos),
+                            )
+                            .to_string();
+                        }
+                        cell(datum, name)
+                    })
+                    .collect()
+            })
+            .collect();
+        let widths: Vec<usize> = (0..header.len())
+            .map(|i| {
+                rows.iter()
+                    .map(|row| row[i].chars().count())
+                    .chain(std::iter::once(header[i].chars().count()))
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        let line = |cells: &[String]| {
+            cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{cell:width$}"))
+                .collect::<Vec<_>>()
+                .join(" | ")
+        };
+        let mut out = format!(
+            "{metric} -> table {table}, {rows} rows\n{header}\n{rule}\n",
+            table = clean_id(metric),
+            rows = datums.len(),
+            header = line(&header),
+            rule = widths
+                .iter()
+                .map(|width| "-".repeat(*width))
+                .collect::<Vec<_>>()
+                .join("-+-"),
+        );
+        for row in &rows {
+            out.push_str(&line(row));
+            out.push('\n');
+        }
+        out
+    }
+}
+
+fn cell(datum: &Datum, name: &str) -> String {
+    if let Some(value) = datum.dimensions.get(name).and_then(|d| d.value.as_ref()) {
+        return match value {
+            dimension::Value::String(s) => s.clone(),
+            dimension::Value::Number(n) => n.to_string(),
+            dimension::Value::Boolean(b) => b.to_string(),
+        };
+    }
+    match datum.measurements.get(name).and_then(|m| m.value.as_ref()) {
+        Some(value) => match value {
+            measurement::Value::I64(i) => i.to_string(),
+            measurement::Value::I32(i) => i.to_string(),
+            measurement::Value::F64(f) => f.to_string(),
+            measurement::Value::F32(f) => f.to_string(),
+            measurement::Value::StatisticSet(s) => SqlStatisticSet::from(s.clone()).to_string(),
+            measurement::Value::Histogram(h) => to_jsonmap(h).to_string(),
+            measurement::Value::Tdigest(t) => SqlTdigest::from(t).to_string(),
+        },
+        None => String::new(),
+    }
+}
@@ -0,0 +1,206 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use communication::proto::goodmetrics::Datum;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
+    task,
+    time::{interval, timeout_at, Instant, MissedTickBehavior},
+};
+
+use crate::config::options::Options;
+
+use super::{metricssendqueue::MetricsReceiveQueue, sink_error::SinkError};
+
+#[derive(Debug, Clone)]
+struct FileConfig {
+    pub directory: PathBuf,
+    pub max_segment_bytes: u64,
+    pub max_segment_age: Duration,
+    pub gzip: bool,
+}
+
+struct Segment {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    bytes: u64,
+    opened: Instant,
+}
+
+/// Appends every datum as a line of json, in the same format `goodmetrics send` accepts.
+/// Segments are named for the unix second they were opened, so they sort by time.
+pub struct FileSender {
+    rx: MetricsReceiveQueue,
+    configuration: FileConfig,
+    segment: Option<Segment>,
+}
+
+impl FileSender {
+    pub async fn new(rx: MetricsReceiveQueue, options: Options) -> Result<FileSender, SinkError> {
+        let directory = PathBuf::from(options.file_sink_directory.unwrap_or_default());
+        tokio::fs::create_dir_all(&directory)
+            .await
+            .map_err(|e| SinkError::other("could not create file sink directory", Box::new(e)))?;
+
+        Ok(FileSender {
+            rx,
+            configuration: FileConfig {
+                directory,
+                max_segment_bytes: options.file_sink_max_segment_bytes,
+                max_segment_age: options.file_sink_max_segment_age,
+                gzip: options.file_sink_gzip,
+            },
+            segment: None,
+        })
+    }
+
+    pub async fn consume_stuff(mut self) -> Result<u32, SinkError> {
+        log::info!(
+            "started file consumer in {:?}",
+            self.configuration.directory
+        );
+
+        // An idle daemon still closes, and gzips, its last segment once it's old enough.
+        let mut age_check = interval(Duration::from_secs(1));
+        age_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            let mut batch = tokio::select! {
+                batch = self.rx.recv() => match batch {
+                    Some(batch) => batch,
+                    None => break,
+                },
+                _ = age_check.tick() => {
+                    if self.segment_is_old() {
+                        if let Err(e) = self.close_segment().await {
+                            log::error!("failed to close an old segment: {:?}", e);
+                        }
+                    }
+                    continue;
+                }
+            };
+            let deadline = Instant::now() + Duration::from_secs(1);
+            while let Ok(Some(mut extras)) = timeout_at(deadline, self.rx.recv()).await {
+                batch.append(&mut extras);
+            }
+
+            if let Err(e) = self.write_batch(&batch).await {
+                log::error!(
+                    "dropping {} datums from the file sink: {:?}",
+                    batch.len(),
+                    e
+                );
+                // Start over with a fresh segment rather than appending to a broken one.
+                self.segment = None;
+            }
+        }
+
+        self.close_segment().await?;
+        log::info!("ended file consumer");
+        Ok(1)
+    }
+
+    fn segment_is_old(&self) -> bool {
+        match &self.segment {
+            Some(segment) => self.configuration.max_segment_age <= segment.opened.elapsed(),
+            None => false,
+        }
+    }
+
+    async fn write_batch(&mut self, batch: &[Datum]) -> Result<(), SinkError> {
+        let should_rotate = self.segment_is_old()
+            || match &self.segment {
+                Some(segment) => self.configuration.max_segment_bytes <= segment.bytes,
+                None => false,
+            };
+        if should_rotate {
+            self.close_segment().await?;
+        }
+        if self.segment.is_none() {
+            self.segment = Some(open_segment(&self.configuration.directory).await?);
+        }
+        let segment = self.segment.as_mut().expect("segment was just opened");
+
+        let mut buffer = Vec::with_capacity(batch.len() * 256);
+        for datum in batch {
+            serde_json::to_writer(&mut buffer, datum)
+                .map_err(|e| SinkError::other("failed serializing datum", Box::new(e)))?;
+            buffer.push(b'\n');
+        }
+        segment
+            .writer
+            .write_all(&buffer)
+            .await
+            .map_err(|e| SinkError::other("failed writing segment", Box::new(e)))?;
+        segment
+            .writer
+            .flush()
+            .await
+            .map_err(|e| SinkError::other("failed flushing segment", Box::new(e)))?;
+        segment.bytes += buffer.len() as u64;
+        log::debug!("wrote {} datums to {:?}", batch.len(), segment.path);
+
+        Ok(())
+    }
+
+    async fn close_segment(&mut self) -> Result<(), SinkError> {
+        let mut segment = match self.segment.take() {
+            Some(segment) => segment,
+            None => return Ok(()),
+        };
+        segment
+            .writer
+            .shutdown()
+            .await
+            .map_err(|e| SinkError::other("failed closing segment", Box::new(e)))?;
+        log::info!("closed segment {:?}", segment.path);
+
+        if self.configuration.gzip {
+            let path = segment.path;
+            // Compression is slow and blocking; don't hold up the next batch for it.
+            task::spawn_blocking(move || {
+                if let Err(e) = gzip_segment(&path) {
+                    log::error!("failed to gzip {:?}: {:?}", path, e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+async fn open_segment(directory: &Path) -> Result<Segment, SinkError> {
+    let opened_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0));
+    let path = directory.join(format!(
+        "goodmetrics-{}-{:09}.ndjson",
+        opened_at.as_secs(),
+        opened_at.subsec_nanos()
+    ));
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+        .map_err(|e| SinkError::other("failed opening segment", Box::new(e)))?;
+    log::info!("opened segment {:?}", path);
+
+    Ok(Segment {
+        path,
+        writer: BufWriter::new(file),
+        bytes: 0,
+        opened: Instant::now(),
+    })
+}
+
+fn gzip_segment(path: &Path) -> std::io::Result<()> {
+    let mut gzipped_path = path.as_os_str().to_owned();
+    gzipped_path.push(".gz");
+
+    let mut input = std::fs::File::open(path)?;
+    let output = std::fs::File::create(&gzipped_path)?;
+    let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    std::fs::remove_file(path)
+}
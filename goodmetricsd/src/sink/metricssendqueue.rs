@@ -1,4 +1,4 @@
-use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::broadcast::{error::RecvError, Receiver, Sender};
 
 use communication::proto::goodmetrics::Datum;
 
@@ -34,12 +34,18 @@ impl MetricsSendQueue {
 }
 
 impl MetricsReceiveQueue {
+    /// None once every sender is gone. A sink that falls behind loses the oldest batches and keeps going.
     pub async fn recv(&mut self) -> Option<Vec<Datum>> {
-        match self.rx.recv().await {
-            Ok(some_datums) => Some(some_datums),
-            Err(error) => {
-                log::error!("failed to receive some datums: {:?}", error);
-                None
+        loop {
+            match self.rx.recv().await {
+                Ok(some_datums) => return Some(some_datums),
+                Err(RecvError::Lagged(skipped)) => {
+                    log::error!("fell behind and dropped {skipped} batches of datums");
+                }
+                Err(error) => {
+                    log::error!("failed to receive some datums: {:?}", error);
+                    return None;
+                }
             }
         }
     }
@@ -1,9 +1,22 @@
+use std::collections::BTreeMap;
+
 use communication::proto::goodmetrics::Datum;
+use itertools::Itertools;
 
+pub mod clickhouse_sink;
+pub mod debug_sink;
+pub mod file_sink;
 pub mod metricssendqueue;
+pub mod opentelemetry_cumulative;
+pub mod opentelemetry_json;
 pub mod opentelemetry_sink;
+pub mod parquet_sink;
 pub mod postgres_sink;
+pub mod prometheus_sink;
+pub mod relay_sink;
+pub mod remote_write_sink;
 pub mod sink_error;
+pub mod sqlite_sink;
 
 pub trait MetricsSink: Send {
     fn drain(&self, metrics: Vec<Datum>) -> Result<String, ErrorCode>;
@@ -13,3 +26,15 @@ pub trait MetricsSink: Send {
 pub enum ErrorCode {
     QueueFull,
 }
+
+fn group_metrics(batch: Vec<Datum>) -> BTreeMap<String, Vec<Datum>> {
+    let grouped_metrics: BTreeMap<String, Vec<Datum>> = batch
+        .into_iter()
+        // TODO: fix string copying here
+        .sorted_by_key(|d| d.metric.clone())
+        .group_by(|d| d.metric.clone())
+        .into_iter()
+        .map(|(metric, datums_iterable)| (metric, datums_iterable.collect::<Vec<Datum>>()))
+        .collect();
+    grouped_metrics
+}
@@ -0,0 +1,663 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use communication::proto::opentelemetry::{
+    common::v1::KeyValue, metrics::v1 as opentelemetry_metrics,
+};
+use tokio::time::Instant;
+
+use super::opentelemetry_sink::MAX_EXPONENTIAL_BUCKETS;
+
+use opentelemetry_metrics::{
+    exponential_histogram_data_point::Buckets, metric::Data, number_data_point,
+    AggregationTemporality,
+};
+
+/// Metric name and its sorted, rendered attributes
+type SeriesKey = (String, String);
+
+#[derive(Debug, Clone)]
+enum Accumulated {
+    Int(i64),
+    Double(f64),
+    Histogram {
+        /// upper bound -> count. Deltas usually only have the buckets that saw data, so the totals
+        /// keep every bound any of them had.
+        buckets: BTreeMap<Bound, u64>,
+        /// Above the highest bound
+        overflow: u64,
+        count: u64,
+        sum: Option<f64>,
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+    ExponentialHistogram {
+        scale: i32,
+        zero_count: u64,
+        /// bucket index -> count
+        positive: BTreeMap<i32, u64>,
+        negative: BTreeMap<i32, u64>,
+        count: u64,
+        sum: Option<f64>,
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+}
+
+/// An explicit histogram bound, ordered so it can key a map
+#[derive(Debug, Clone, Copy)]
+struct Bound(f64);
+
+impl PartialEq for Bound {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.total_cmp(&other.0).is_eq()
+    }
+}
+
+impl Eq for Bound {}
+
+impl PartialOrd for Bound {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Bound {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+#[derive(Debug)]
+struct Series {
+    start_time_unix_nano: u64,
+    time_unix_nano: u64,
+    updated: Instant,
+    value: Accumulated,
+}
+
+/// Accumulates delta sums and histograms into cumulative ones, for backends that only take cumulative.
+/// A series is a metric name and its attributes. Series that go quiet for the expiry, change type,
+/// or go down when they're monotonic start over with a new start time.
+pub struct CumulativeStage {
+    expiry: Duration,
+    series: HashMap<SeriesKey, Series>,
+}
+
+impl CumulativeStage {
+    pub fn new(expiry: Duration) -> CumulativeStage {
+        CumulativeStage {
+            expiry,
+            series: HashMap::new(),
+        }
+    }
+
+    pub fn accumulate(
+        &mut self,
+        mut metrics: Vec<opentelemetry_metrics::Metric>,
+    ) -> Vec<opentelemetry_metrics::Metric> {
+        let expiry = self.expiry;
+        self.series
+            .retain(|_, series| series.updated.elapsed() < expiry);
+
+        let delta = AggregationTemporality::Delta as i32;
+        let cumulative = AggregationTemporality::Cumulative as i32;
+        for metric in &mut metrics {
+            match &mut metric.data {
+                Some(Data::Sum(sum)) if sum.aggregation_temporality == delta => {
+                    for point in &mut sum.data_points {
+                        self.accumulate_number(&metric.name, sum.is_monotonic, point);
+                    }
+                    sum.aggregation_temporality = cumulative;
+                }
+                Some(Data::Histogram(histogram)) if histogram.aggregation_temporality == delta => {
+                    for point in &mut histogram.data_points {
+                        self.accumulate_histogram(&metric.name, point);
+                    }
+                    histogram.aggregation_temporality = cumulative;
+                }
+                Some(Data::ExponentialHistogram(histogram))
+                    if histogram.aggregation_temporality == delta =>
+                {
+                    for point in &mut histogram.data_points {
+                        self.accumulate_exponential_histogram(&metric.name, point);
+                    }
+                    histogram.aggregation_temporality = cumulative;
+                }
+                // Gauges, summaries and sums that are already cumulative pass through.
+                _ => {}
+            }
+        }
+        metrics
+    }
+
+    fn accumulate_number(
+        &mut self,
+        name: &str,
+        is_monotonic: bool,
+        point: &mut opentelemetry_metrics::NumberDataPoint,
+    ) {
+        let key = series_key(name, &point.attributes);
+        let delta = match point.value {
+            Some(number_data_point::Value::AsInt(i)) => Accumulated::Int(i),
+            Some(number_data_point::Value::AsDouble(f)) => Accumulated::Double(f),
+            None => return,
+        };
+        let merged = self
+            .series
+            .get(&key)
+            .and_then(|series| match (&series.value, &delta) {
+                (Accumulated::Int(total), Accumulated::Int(i)) if !(is_monotonic && *i < 0) => {
+                    Some(Accumulated::Int(total.wrapping_add(*i)))
+                }
+                (Accumulated::Double(total), Accumulated::Double(f))
+                    if !(is_monotonic && *f < 0.0) =>
+                {
+                    Some(Accumulated::Double(total + f))
+                }
+                // A different number type or a monotonic sum going down means the source restarted.
+                _ => None,
+            });
+
+        let series = self.update(
+            key,
+            merged,
+            delta,
+            point.start_time_unix_nano,
+            point.time_unix_nano,
+        );
+        point.start_time_unix_nano = series.start_time_unix_nano;
+        point.time_unix_nano = series.time_unix_nano;
+        match series.value {
+            Accumulated::Int(i) => point.value = Some(number_data_point::Value::AsInt(i)),
+            Accumulated::Double(f) => point.value = Some(number_data_point::Value::AsDouble(f)),
+            _ => {}
+        }
+    }
+
+    fn accumulate_histogram(
+        &mut self,
+        name: &str,
+        point: &mut opentelemetry_metrics::HistogramDataPoint,
+    ) {
+        let key = series_key(name, &point.attributes);
+        let delta = Accumulated::Histogram {
+            buckets: point
+                .explicit_bounds
+                .iter()
+                .zip(&point.bucket_counts)
+                .map(|(bound, count)| (Bound(*bound), *count))
+                .collect(),
+            overflow: point
+                .bucket_counts
+                .get(point.explicit_bounds.len())
+                .copied()
+                .unwrap_or_default(),
+            count: point.count,
+            sum: point.sum,
+            min: point.min,
+            max: point.max,
+        };
+        let merged = self
+            .series
+            .get(&key)
+            .and_then(|series| match (&series.value, &delta) {
+                (
+                    Accumulated::Histogram {
+                        buckets,
+                        overflow,
+                        count,
+                        sum,
+                        min,
+                        max,
+                    },
+                    Accumulated::Histogram {
+                        buckets: delta_buckets,
+                        overflow: delta_overflow,
+                        count: delta_count,
+                        sum: delta_sum,
+                        min: delta_min,
+                        max: delta_max,
+                    },
+                ) => {
+                    // Counts merge by their upper bound, like goodmetrics buckets do.
+                    let mut merged_buckets = buckets.clone();
+                    for (bound, count) in delta_buckets {
+                        *merged_buckets.entry(*bound).or_default() += count;
+                    }
+                    Some(Accumulated::Histogram {
+                        buckets: merged_buckets,
+                        overflow: overflow + delta_overflow,
+                        count: count + delta_count,
+                        sum: add(*sum, *delta_sum),
+                        min: lesser(*min, *delta_min),
+                        max: greater(*max, *delta_max),
+                    })
+                }
+                _ => None,
+            });
+
+        let series = self.update(
+            key,
+            merged,
+            delta,
+            point.start_time_unix_nano,
+            point.time_unix_nano,
+        );
+        point.start_time_unix_nano = series.start_time_unix_nano;
+        point.time_unix_nano = series.time_unix_nano;
+        if let Accumulated::Histogram {
+            buckets,
+            overflow,
+            count,
+            sum,
+            min,
+            max,
+        } = &series.value
+        {
+            point.explicit_bounds = buckets.keys().map(|bound| bound.0).collect();
+            point.bucket_counts = buckets
+                .values()
+                .copied()
+                .chain(std::iter::once(*overflow))
+                .collect();
+            point.count = *count;
+            point.sum = *sum;
+            point.min = *min;
+            point.max = *max;
+        }
+    }
+
+    fn accumulate_exponential_histogram(
+        &mut self,
+        name: &str,
+        point: &mut opentelemetry_metrics::ExponentialHistogramDataPoint,
+    ) {
+        let key = series_key(name, &point.attributes);
+        let delta = Accumulated::ExponentialHistogram {
+            scale: point.scale,
+            zero_count: point.zero_count,
+            positive: bucket_map(point.positive.as_ref()),
+            negative: bucket_map(point.negative.as_ref()),
+            count: point.count,
+            sum: point.sum,
+            min: point.min,
+            max: point.max,
+        };
+        let merged = self
+            .series
+            .get(&key)
+            .and_then(|series| match (&series.value, &delta) {
+                (
+                    Accumulated::ExponentialHistogram {
+                        scale,
+                        zero_count,
+                        positive,
+                        negative,
+                        count,
+                        sum,
+                        min,
+                        max,
+                    },
+                    Accumulated::ExponentialHistogram {
+                        scale: delta_scale,
+                        zero_count: delta_zero_count,
+                        positive: delta_positive,
+                        negative: delta_negative,
+                        count: delta_count,
+                        sum: delta_sum,
+                        min: delta_min,
+                        max: delta_max,
+                    },
+                ) => {
+                    // Merge at the coarser scale, then keep coarsening until the buckets fit.
+                    let mut merged_scale = *scale.min(delta_scale);
+                    let mut positive = merge_buckets(
+                        &downscale(positive, scale - merged_scale),
+                        &downscale(delta_positive, delta_scale - merged_scale),
+                    );
+                    let mut negative = merge_buckets(
+                        &downscale(negative, scale - merged_scale),
+                        &downscale(delta_negative, delta_scale - merged_scale),
+                    );
+                    while MAX_EXPONENTIAL_BUCKETS < bucket_span(&positive)
+                        || MAX_EXPONENTIAL_BUCKETS < bucket_span(&negative)
+                    {
+                        merged_scale -= 1;
+                        positive = downscale(&positive, 1);
+                        negative = downscale(&negative, 1);
+                    }
+                    Some(Accumulated::ExponentialHistogram {
+                        scale: merged_scale,
+                        zero_count: zero_count + delta_zero_count,
+                        positive,
+                        negative,
+                        count: count + delta_count,
+                        sum: add(*sum, *delta_sum),
+                        min: lesser(*min, *delta_min),
+                        max: greater(*max, *delta_max),
+                    })
+                }
+                _ => None,
+            });
+
+        let series = self.update(
+            key,
+            merged,
+            delta,
+            point.start_time_unix_nano,
+            point.time_unix_nano,
+        );
+        point.start_time_unix_nano = series.start_time_unix_nano;
+        point.time_unix_nano = series.time_unix_nano;
+        if let Accumulated::ExponentialHistogram {
+            scale,
+            zero_count,
+            positive,
+            negative,
+            count,
+            sum,
+            min,
+            max,
+        } = &series.value
+        {
+            point.scale = *scale;
+            point.zero_count = *zero_count;
+            point.positive = Some(buckets(positive));
+            point.negative = Some(buckets(negative));
+            point.count = *count;
+            point.sum = *sum;
+            point.min = *min;
+            point.max = *max;
+        }
+    }
+
+    /// Stores the merged value, or starts the series over from this delta when it couldn't be merged.
+    fn update(
+        &mut self,
+        key: SeriesKey,
+        merged: Option<Accumulated>,
+        delta: Accumulated,
+        start_time_unix_nano: u64,
+        time_unix_nano: u64,
+    ) -> &Series {
+        let series = match (merged, self.series.remove(&key)) {
+            (Some(value), Some(previous)) => Series {
+                start_time_unix_nano: previous.start_time_unix_nano,
+                // Cumulative points can't go back in time, even when their deltas arrive out of order.
+                time_unix_nano: time_unix_nano.max(previous.time_unix_nano),
+                updated: Instant::now(),
+                value,
+            },
+            (_, previous) => {
+                if previous.is_some() {
+                    log::debug!("resetting cumulative series {}{{{}}}", key.0, key.1);
+                }
+                Series {
+                    start_time_unix_nano: if start_time_unix_nano == 0 {
+                        time_unix_nano
+                    } else {
+                        start_time_unix_nano
+                    },
+                    time_unix_nano,
+                    updated: Instant::now(),
+                    value: delta,
+                }
+            }
+        };
+        self.series.entry(key).or_insert(series)
+    }
+}
+
+fn series_key(name: &str, attributes: &[KeyValue]) -> SeriesKey {
+    let mut attributes: Vec<String> = attributes
+        .iter()
+        .map(|attribute| format!("{}={:?}", attribute.key, attribute.value))
+        .collect();
+    attributes.sort();
+    (name.to_string(), attributes.join(","))
+}
+
+fn add(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    Some(a? + b?)
+}
+
+fn lesser(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn greater(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn bucket_map(buckets: Option<&Buckets>) -> BTreeMap<i32, u64> {
+    buckets
+        .map(|buckets| {
+            buckets
+                .bucket_counts
+                .iter()
+                .enumerate()
+                .filter(|(_, count)| 0 < **count)
+                .map(|(i, count)| (buckets.offset + i as i32, *count))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn buckets(map: &BTreeMap<i32, u64>) -> Buckets {
+    let offset = map.keys().next().copied().unwrap_or_default();
+    let mut bucket_counts = vec![0; bucket_span(map) as usize];
+    for (index, count) in map {
+        bucket_counts[(index - offset) as usize] = *count;
+    }
+    Buckets {
+        offset,
+        bucket_counts,
+    }
+}
+
+fn bucket_span(map: &BTreeMap<i32, u64>) -> i32 {
+    match (map.keys().next(), map.keys().next_back()) {
+        (Some(low), Some(high)) => high - low + 1,
+        _ => 0,
+    }
+}
+
+/// Each step down in scale merges pairs of neighboring buckets.
+fn downscale(map: &BTreeMap<i32, u64>, by: i32) -> BTreeMap<i32, u64> {
+    let mut downscaled = BTreeMap::new();
+    for (index, count) in map {
+        *downscaled.entry(index >> by).or_default() += count;
+    }
+    downscaled
+}
+
+fn merge_buckets(a: &BTreeMap<i32, u64>, b: &BTreeMap<i32, u64>) -> BTreeMap<i32, u64> {
+    let mut merged = a.clone();
+    for (index, count) in b {
+        *merged.entry(*index).or_default() += count;
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use communication::proto::opentelemetry::metrics::v1 as opentelemetry_metrics;
+    use opentelemetry_metrics::{
+        exponential_histogram_data_point::Buckets, metric::Data, number_data_point,
+        AggregationTemporality, ExponentialHistogram, ExponentialHistogramDataPoint, Histogram,
+        HistogramDataPoint, Metric, NumberDataPoint, Sum,
+    };
+
+    use super::CumulativeStage;
+
+    fn sum(value: i64, time: u64) -> Metric {
+        Metric {
+            name: "requests".to_string(),
+            data: Some(Data::Sum(Sum {
+                data_points: vec![NumberDataPoint {
+                    start_time_unix_nano: time - 1,
+                    time_unix_nano: time,
+                    value: Some(number_data_point::Value::AsInt(value)),
+                    ..Default::default()
+                }],
+                aggregation_temporality: AggregationTemporality::Delta as i32,
+                is_monotonic: true,
+            })),
+            ..Default::default()
+        }
+    }
+
+    fn histogram(bounds: &[f64], bucket_counts: &[u64], time: u64) -> Metric {
+        Metric {
+            name: "latency".to_string(),
+            data: Some(Data::Histogram(Histogram {
+                data_points: vec![HistogramDataPoint {
+                    start_time_unix_nano: time - 1,
+                    time_unix_nano: time,
+                    count: bucket_counts.iter().sum(),
+                    bucket_counts: bucket_counts.to_vec(),
+                    explicit_bounds: bounds.to_vec(),
+                    ..Default::default()
+                }],
+                aggregation_temporality: AggregationTemporality::Delta as i32,
+            })),
+            ..Default::default()
+        }
+    }
+
+    fn exponential(scale: i32, offset: i32, bucket_counts: &[u64], time: u64) -> Metric {
+        Metric {
+            name: "size".to_string(),
+            data: Some(Data::ExponentialHistogram(ExponentialHistogram {
+                data_points: vec![ExponentialHistogramDataPoint {
+                    start_time_unix_nano: time - 1,
+                    time_unix_nano: time,
+                    count: bucket_counts.iter().sum(),
+                    scale,
+                    positive: Some(Buckets {
+                        offset,
+                        bucket_counts: bucket_counts.to_vec(),
+                    }),
+                    ..Default::default()
+                }],
+                aggregation_temporality: AggregationTemporality::Delta as i32,
+            })),
+            ..Default::default()
+        }
+    }
+
+    fn sum_point(metrics: Vec<Metric>) -> NumberDataPoint {
+        match metrics.into_iter().next().and_then(|metric| metric.data) {
+            Some(Data::Sum(sum)) => {
+                assert_eq!(
+                    sum.aggregation_temporality,
+                    AggregationTemporality::Cumulative as i32
+                );
+                sum.data_points.into_iter().next().expect("a point")
+            }
+            other => panic!("not a sum: {other:?}"),
+        }
+    }
+
+    fn histogram_point(metrics: Vec<Metric>) -> HistogramDataPoint {
+        match metrics.into_iter().next().and_then(|metric| metric.data) {
+            Some(Data::Histogram(histogram)) => {
+                histogram.data_points.into_iter().next().expect("a point")
+            }
+            other => panic!("not a histogram: {other:?}"),
+        }
+    }
+
+    fn exponential_point(metrics: Vec<Metric>) -> ExponentialHistogramDataPoint {
+        match metrics.into_iter().next().and_then(|metric| metric.data) {
+            Some(Data::ExponentialHistogram(histogram)) => {
+                histogram.data_points.into_iter().next().expect("a point")
+            }
+            other => panic!("not an exponential histogram: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn sums_add_up_from_the_first_start_time() {
+        let mut stage = CumulativeStage::new(Duration::from_secs(60));
+        stage.accumulate(vec![sum(3, 10)]);
+        let point = sum_point(stage.accumulate(vec![sum(4, 20)]));
+
+        assert_eq!(point.value, Some(number_data_point::Value::AsInt(7)));
+        assert_eq!(point.start_time_unix_nano, 9);
+        assert_eq!(point.time_unix_nano, 20);
+    }
+
+    #[test]
+    fn monotonic_sum_going_down_resets() {
+        let mut stage = CumulativeStage::new(Duration::from_secs(60));
+        stage.accumulate(vec![sum(3, 10)]);
+        let point = sum_point(stage.accumulate(vec![sum(-1, 20)]));
+
+        assert_eq!(point.value, Some(number_data_point::Value::AsInt(-1)));
+        assert_eq!(point.start_time_unix_nano, 19);
+    }
+
+    #[test]
+    fn expired_series_start_over() {
+        let mut stage = CumulativeStage::new(Duration::ZERO);
+        stage.accumulate(vec![sum(3, 10)]);
+        let point = sum_point(stage.accumulate(vec![sum(4, 20)]));
+
+        assert_eq!(point.value, Some(number_data_point::Value::AsInt(4)));
+        assert_eq!(point.start_time_unix_nano, 19);
+    }
+
+    #[test]
+    fn histograms_with_different_bounds_merge_by_bound() {
+        let mut stage = CumulativeStage::new(Duration::from_secs(60));
+        stage.accumulate(vec![histogram(&[1.0, 10.0], &[2, 3, 0], 10)]);
+        let point =
+            histogram_point(stage.accumulate(vec![histogram(&[5.0, 10.0], &[1, 4, 0], 20)]));
+
+        assert_eq!(point.explicit_bounds, vec![1.0, 5.0, 10.0]);
+        assert_eq!(point.bucket_counts, vec![2, 1, 7, 0]);
+        assert_eq!(point.count, 10);
+        assert_eq!(point.start_time_unix_nano, 9);
+    }
+
+    #[test]
+    fn histograms_keep_bounds_a_delta_is_missing() {
+        let mut stage = CumulativeStage::new(Duration::from_secs(60));
+        stage.accumulate(vec![histogram(&[1.0, 10.0], &[2, 3, 0], 10)]);
+        let point = histogram_point(stage.accumulate(vec![histogram(&[], &[1], 20)]));
+
+        assert_eq!(point.explicit_bounds, vec![1.0, 10.0]);
+        assert_eq!(point.bucket_counts, vec![2, 3, 1]);
+        assert_eq!(point.count, 6);
+    }
+
+    #[test]
+    fn exponential_histograms_merge_at_the_coarser_scale() {
+        let mut stage = CumulativeStage::new(Duration::from_secs(60));
+        // Scale 1 buckets 2 and 3 are scale 0 bucket 1.
+        stage.accumulate(vec![exponential(1, 2, &[1, 2], 10)]);
+        let point = exponential_point(stage.accumulate(vec![exponential(0, 1, &[4], 20)]));
+
+        assert_eq!(point.scale, 0);
+        assert_eq!(
+            point.positive,
+            Some(Buckets {
+                offset: 1,
+                bucket_counts: vec![7],
+            })
+        );
+        assert_eq!(point.count, 7);
+    }
+}
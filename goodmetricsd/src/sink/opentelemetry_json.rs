@@ -0,0 +1,434 @@
+//! The otlp/http json body. Otlp json is protobuf's json mapping with a few twists: lowerCamelCase
+//! field names, oneofs inlined into their message, enums as integers and trace and span ids as
+//! hex. 64 bit integers are written as strings, like protobuf's json mapping does.
+
+use communication::proto::opentelemetry::{
+    collector::metrics::v1::ExportMetricsServiceRequest,
+    common::v1::{any_value, AnyValue, InstrumentationScope, KeyValue},
+    metrics::v1 as opentelemetry_metrics,
+    resource::v1::Resource,
+};
+use serde_json::{json, Map, Value};
+
+use opentelemetry_metrics::{
+    exemplar, exponential_histogram_data_point::Buckets, metric::Data, number_data_point,
+};
+
+pub fn export_request(request: &ExportMetricsServiceRequest) -> Value {
+    json!({
+        "resourceMetrics": request.resource_metrics.iter().map(resource_metrics).collect::<Vec<_>>(),
+    })
+}
+
+fn resource_metrics(resource_metrics: &opentelemetry_metrics::ResourceMetrics) -> Value {
+    let mut object = Map::new();
+    if let Some(resource) = &resource_metrics.resource {
+        object.insert("resource".to_string(), self::resource(resource));
+    }
+    object.insert(
+        "scopeMetrics".to_string(),
+        resource_metrics
+            .scope_metrics
+            .iter()
+            .map(scope_metrics)
+            .collect(),
+    );
+    object.insert(
+        "schemaUrl".to_string(),
+        resource_metrics.schema_url.clone().into(),
+    );
+    Value::Object(object)
+}
+
+fn resource(resource: &Resource) -> Value {
+    json!({
+        "attributes": attributes(&resource.attributes),
+        "droppedAttributesCount": resource.dropped_attributes_count,
+    })
+}
+
+fn scope_metrics(scope_metrics: &opentelemetry_metrics::ScopeMetrics) -> Value {
+    let mut object = Map::new();
+    if let Some(scope) = &scope_metrics.scope {
+        object.insert("scope".to_string(), self::scope(scope));
+    }
+    object.insert(
+        "metrics".to_string(),
+        scope_metrics.metrics.iter().map(metric).collect(),
+    );
+    object.insert(
+        "schemaUrl".to_string(),
+        scope_metrics.schema_url.clone().into(),
+    );
+    Value::Object(object)
+}
+
+fn scope(scope: &InstrumentationScope) -> Value {
+    json!({
+        "name": scope.name,
+        "version": scope.version,
+        "attributes": attributes(&scope.attributes),
+        "droppedAttributesCount": scope.dropped_attributes_count,
+    })
+}
+
+fn metric(metric: &opentelemetry_metrics::Metric) -> Value {
+    let mut object = Map::new();
+    object.insert("name".to_string(), metric.name.clone().into());
+    object.insert("description".to_string(), metric.description.clone().into());
+    object.insert("unit".to_string(), metric.unit.clone().into());
+    match &metric.data {
+        Some(Data::Gauge(gauge)) => {
+            object.insert(
+                "gauge".to_string(),
+                json!({
+                    "dataPoints": gauge.data_points.iter().map(number_point).collect::<Vec<_>>(),
+                }),
+            );
+        }
+        Some(Data::Sum(sum)) => {
+            object.insert(
+                "sum".to_string(),
+                json!({
+                    "dataPoints": sum.data_points.iter().map(number_point).collect::<Vec<_>>(),
+                    "aggregationTemporality": sum.aggregation_temporality,
+                    "isMonotonic": sum.is_monotonic,
+                }),
+            );
+        }
+        Some(Data::Histogram(histogram)) => {
+            object.insert(
+                "histogram".to_string(),
+                json!({
+                    "dataPoints": histogram.data_points.iter().map(histogram_point).collect::<Vec<_>>(),
+                    "aggregationTemporality": histogram.aggregation_temporality,
+                }),
+            );
+        }
+        Some(Data::ExponentialHistogram(histogram)) => {
+            object.insert(
+                "exponentialHistogram".to_string(),
+                json!({
+                    "dataPoints": histogram.data_points.iter().map(exponential_point).collect::<Vec<_>>(),
+                    "aggregationTemporality": histogram.aggregation_temporality,
+                }),
+            );
+        }
+        Some(Data::Summary(summary)) => {
+            object.insert(
+                "summary".to_string(),
+                json!({
+                    "dataPoints": summary.data_points.iter().map(summary_point).collect::<Vec<_>>(),
+                }),
+            );
+        }
+        None => {}
+    }
+    Value::Object(object)
+}
+
+fn number_point(point: &opentelemetry_metrics::NumberDataPoint) -> Value {
+    let mut object = Map::new();
+    object.insert("attributes".to_string(), attributes(&point.attributes));
+    object.insert(
+        "startTimeUnixNano".to_string(),
+        point.start_time_unix_nano.to_string().into(),
+    );
+    object.insert(
+        "timeUnixNano".to_string(),
+        point.time_unix_nano.to_string().into(),
+    );
+    match point.value {
+        Some(number_data_point::Value::AsDouble(value)) => {
+            object.insert("asDouble".to_string(), double(value));
+        }
+        Some(number_data_point::Value::AsInt(value)) => {
+            object.insert("asInt".to_string(), value.to_string().into());
+        }
+        None => {}
+    }
+    object.insert("exemplars".to_string(), exemplars(&point.exemplars));
+    object.insert("flags".to_string(), point.flags.into());
+    Value::Object(object)
+}
+
+fn histogram_point(point: &opentelemetry_metrics::HistogramDataPoint) -> Value {
+    let mut object = Map::new();
+    object.insert("attributes".to_string(), attributes(&point.attributes));
+    object.insert(
+        "startTimeUnixNano".to_string(),
+        point.start_time_unix_nano.to_string().into(),
+    );
+    object.insert(
+        "timeUnixNano".to_string(),
+        point.time_unix_nano.to_string().into(),
+    );
+    object.insert("count".to_string(), point.count.to_string().into());
+    optional_double(&mut object, "sum", point.sum);
+    object.insert(
+        "bucketCounts".to_string(),
+        point
+            .bucket_counts
+            .iter()
+            .map(|count| Value::from(count.to_string()))
+            .collect(),
+    );
+    object.insert(
+        "explicitBounds".to_string(),
+        point.explicit_bounds.iter().copied().map(double).collect(),
+    );
+    object.insert("exemplars".to_string(), exemplars(&point.exemplars));
+    object.insert("flags".to_string(), point.flags.into());
+    optional_double(&mut object, "min", point.min);
+    optional_double(&mut object, "max", point.max);
+    Value::Object(object)
+}
+
+fn exponential_point(point: &opentelemetry_metrics::ExponentialHistogramDataPoint) -> Value {
+    let mut object = Map::new();
+    object.insert("attributes".to_string(), attributes(&point.attributes));
+    object.insert(
+        "startTimeUnixNano".to_string(),
+        point.start_time_unix_nano.to_string().into(),
+    );
+    object.insert(
+        "timeUnixNano".to_string(),
+        point.time_unix_nano.to_string().into(),
+    );
+    object.insert("count".to_string(), point.count.to_string().into());
+    optional_double(&mut object, "sum", point.sum);
+    object.insert("scale".to_string(), point.scale.into());
+    object.insert("zeroCount".to_string(), point.zero_count.to_string().into());
+    if let Some(positive) = &point.positive {
+        object.insert("positive".to_string(), buckets(positive));
+    }
+    if let Some(negative) = &point.negative {
+        object.insert("negative".to_string(), buckets(negative));
+    }
+    object.insert("flags".to_string(), point.flags.into());
+    object.insert("exemplars".to_string(), exemplars(&point.exemplars));
+    optional_double(&mut object, "min", point.min);
+    optional_double(&mut object, "max", point.max);
+    object.insert("zeroThreshold".to_string(), double(point.zero_threshold));
+    Value::Object(object)
+}
+
+fn buckets(buckets: &Buckets) -> Value {
+    json!({
+        "offset": buckets.offset,
+        "bucketCounts": buckets.bucket_counts.iter().map(|count| count.to_string()).collect::<Vec<_>>(),
+    })
+}
+
+fn summary_point(point: &opentelemetry_metrics::SummaryDataPoint) -> Value {
+    json!({
+        "attributes": attributes(&point.attributes),
+        "startTimeUnixNano": point.start_time_unix_nano.to_string(),
+        "timeUnixNano": point.time_unix_nano.to_string(),
+        "count": point.count.to_string(),
+        "sum": double(point.sum),
+        "quantileValues": point
+            .quantile_values
+            .iter()
+            .map(|quantile| json!({
+                "quantile": double(quantile.quantile),
+                "value": double(quantile.value),
+            }))
+            .collect::<Vec<_>>(),
+        "flags": point.flags,
+    })
+}
+
+fn exemplars(exemplars: &[opentelemetry_metrics::Exemplar]) -> Value {
+    exemplars
+        .iter()
+        .map(|exemplar| {
+            let mut object = Map::new();
+            object.insert(
+                "filteredAttributes".to_string(),
+                attributes(&exemplar.filtered_attributes),
+            );
+            object.insert(
+                "timeUnixNano".to_string(),
+                exemplar.time_unix_nano.to_string().into(),
+            );
+            match exemplar.value {
+                Some(exemplar::Value::AsDouble(value)) => {
+                    object.insert("asDouble".to_string(), double(value));
+                }
+                Some(exemplar::Value::AsInt(value)) => {
+                    object.insert("asInt".to_string(), value.to_string().into());
+                }
+                None => {}
+            }
+            object.insert("spanId".to_string(), hex(&exemplar.span_id).into());
+            object.insert("traceId".to_string(), hex(&exemplar.trace_id).into());
+            Value::Object(object)
+        })
+        .collect()
+}
+
+fn attributes(attributes: &[KeyValue]) -> Value {
+    attributes.iter().map(key_value).collect()
+}
+
+fn key_value(key_value: &KeyValue) -> Value {
+    let mut object = Map::new();
+    object.insert("key".to_string(), key_value.key.clone().into());
+    if let Some(value) = &key_value.value {
+        object.insert("value".to_string(), any_value(value));
+    }
+    Value::Object(object)
+}
+
+fn any_value(value: &AnyValue) -> Value {
+    let mut object = Map::new();
+    match &value.value {
+        Some(any_value::Value::StringValue(s)) => {
+            object.insert("stringValue".to_string(), s.clone().into());
+        }
+        Some(any_value::Value::BoolValue(b)) => {
+            object.insert("boolValue".to_string(), (*b).into());
+        }
+        Some(any_value::Value::IntValue(i)) => {
+            object.insert("intValue".to_string(), i.to_string().into());
+        }
+        Some(any_value::Value::DoubleValue(d)) => {
+            object.insert("doubleValue".to_string(), double(*d));
+        }
+        Some(any_value::Value::ArrayValue(array)) => {
+            object.insert(
+                "arrayValue".to_string(),
+                json!({ "values": array.values.iter().map(any_value).collect::<Vec<_>>() }),
+            );
+        }
+        Some(any_value::Value::KvlistValue(list)) => {
+            object.insert(
+                "kvlistValue".to_string(),
+                json!({ "values": attributes(&list.values) }),
+            );
+        }
+        Some(any_value::Value::BytesValue(bytes)) => {
+            object.insert("bytesValue".to_string(), base64(bytes).into());
+        }
+        None => {}
+    }
+    Value::Object(object)
+}
+
+fn optional_double(object: &mut Map<String, Value>, name: &str, value: Option<f64>) {
+    if let Some(value) = value {
+        object.insert(name.to_string(), double(value));
+    }
+}
+
+/// Json has no nan or infinity; protobuf's json mapping spells them as strings.
+fn double(value: f64) -> Value {
+    if value.is_nan() {
+        "NaN".into()
+    } else if value == f64::INFINITY {
+        "Infinity".into()
+    } else if value == f64::NEG_INFINITY {
+        "-Infinity".into()
+    } else {
+        value.into()
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | ((*byte as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use communication::proto::opentelemetry::{
+        collector::metrics::v1::ExportMetricsServiceRequest,
+        common::v1::{any_value, AnyValue, KeyValue},
+        metrics::v1::{
+            metric::Data, number_data_point, Gauge, Metric, NumberDataPoint, ResourceMetrics,
+            ScopeMetrics,
+        },
+    };
+    use serde_json::json;
+
+    use super::{base64, double, export_request};
+
+    #[test]
+    fn oneofs_are_inlined_and_64_bit_integers_are_strings() {
+        let request = ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: None,
+                scope_metrics: vec![ScopeMetrics {
+                    scope: None,
+                    metrics: vec![Metric {
+                        name: "m".to_string(),
+                        description: String::new(),
+                        unit: String::new(),
+                        data: Some(Data::Gauge(Gauge {
+                            data_points: vec![NumberDataPoint {
+                                attributes: vec![KeyValue {
+                                    key: "host".to_string(),
+                                    value: Some(AnyValue {
+                                        value: Some(any_value::Value::StringValue("a".to_string())),
+                                    }),
+                                }],
+                                start_time_unix_nano: 1,
+                                time_unix_nano: 2,
+                                value: Some(number_data_point::Value::AsInt(3)),
+                                exemplars: vec![],
+                                flags: 0,
+                            }],
+                        })),
+                    }],
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        };
+
+        let body = export_request(&request);
+        let point =
+            &body["resourceMetrics"][0]["scopeMetrics"][0]["metrics"][0]["gauge"]["dataPoints"][0];
+        assert_eq!(json!("3"), point["asInt"]);
+        assert_eq!(json!("2"), point["timeUnixNano"]);
+        assert_eq!(
+            json!([{ "key": "host", "value": { "stringValue": "a" } }]),
+            point["attributes"]
+        );
+    }
+
+    #[test]
+    fn doubles_that_json_cannot_hold_are_strings() {
+        assert_eq!(json!("NaN"), double(f64::NAN));
+        assert_eq!(json!("-Infinity"), double(f64::NEG_INFINITY));
+        assert_eq!(json!(1.5), double(1.5));
+    }
+
+    #[test]
+    fn bytes_are_base64() {
+        assert_eq!("", base64(b""));
+        assert_eq!("Zg==", base64(b"f"));
+        assert_eq!("Zm8=", base64(b"fo"));
+        assert_eq!("Zm9v", base64(b"foo"));
+        assert_eq!("Zm9vYg==", base64(b"foob"));
+    }
+}
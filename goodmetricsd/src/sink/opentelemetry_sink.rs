@@ -1,43 +1,268 @@
 use std::collections::BTreeMap;
 use std::time::Duration;
 
+use clap::ValueEnum;
 use communication::proto::goodmetrics;
-use communication::{get_channel, ChannelType};
+use communication::{get_channel_with_client_certificate, ChannelType, ClientCertificate};
+use prost::Message;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    StatusCode,
+};
+use serde_derive::Deserialize;
 use tokio::time::{sleep, timeout_at, Instant};
+use tonic::{
+    metadata::{AsciiMetadataKey, AsciiMetadataValue},
+    Code,
+};
 
 use communication::proto::opentelemetry;
 use communication::proto::opentelemetry::collector::metrics::v1::metrics_service_client::MetricsServiceClient;
-use communication::proto::opentelemetry::collector::metrics::v1::ExportMetricsServiceRequest;
+use communication::proto::opentelemetry::collector::metrics::v1::{
+    ExportMetricsPartialSuccess, ExportMetricsServiceRequest, ExportMetricsServiceResponse,
+};
 use communication::proto::opentelemetry::common::v1::{
-    any_value, AnyValue, InstrumentationLibrary, KeyValue,
+    any_value, AnyValue, InstrumentationScope, KeyValue,
 };
+use communication::proto::opentelemetry::resource::v1::Resource;
 
+use crate::config::options::Options;
+
+use super::opentelemetry_cumulative::CumulativeStage;
+use super::opentelemetry_json;
 use super::sink_error::StringError;
 use super::{metricssendqueue::MetricsReceiveQueue, sink_error::SinkError};
 
 use opentelemetry::metrics::v1 as opentelemetry_metrics;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+pub enum OtlpProtocol {
+    /// The MetricsService Export rpc
+    #[value(name = "grpc")]
+    #[serde(rename = "grpc")]
+    Grpc,
+    /// POST /v1/metrics with a protobuf body
+    #[value(name = "http/protobuf")]
+    #[serde(rename = "http/protobuf")]
+    HttpProtobuf,
+    /// POST /v1/metrics with a json body
+    #[value(name = "http/json")]
+    #[serde(rename = "http/json")]
+    HttpJson,
+}
+
+/// Measurements whose names match the pattern get this kind, unless their client sent a kind.
+#[derive(Debug, Clone, Deserialize)]
+pub struct KindRule {
+    pub pattern: String,
+    pub kind: goodmetrics::MeasurementKind,
+}
+
+/// <pattern>=<kind>, like *_count=monotonic_delta_sum
+pub fn parse_kind_rule(rule: &str) -> Result<KindRule, String> {
+    let (pattern, kind) = split_rule(rule)?;
+    let kind = goodmetrics::MeasurementKind::from_str_name(&format!(
+        "MEASUREMENT_KIND_{}",
+        kind.to_uppercase()
+    ))
+    .filter(|kind| *kind != goodmetrics::MeasurementKind::Unspecified)
+    .ok_or_else(|| {
+        format!("unknown kind {kind}. Use gauge, delta_sum, cumulative_sum, monotonic_delta_sum or monotonic_cumulative_sum")
+    })?;
+    Ok(KindRule { pattern, kind })
+}
+
+/// Measurements whose names match the pattern get this unit.
+#[derive(Debug, Clone, Deserialize)]
+pub struct UnitRule {
+    pub pattern: String,
+    pub unit: String,
+}
+
+/// <pattern>=<unit>, like *_ms=ms
+pub fn parse_unit_rule(rule: &str) -> Result<UnitRule, String> {
+    let (pattern, unit) = split_rule(rule)?;
+    Ok(UnitRule { pattern, unit })
+}
+
+fn split_rule(rule: &str) -> Result<(String, String), String> {
+    match rule.rsplit_once('=') {
+        Some((pattern, value)) if !pattern.trim().is_empty() && !value.trim().is_empty() => {
+            Ok((pattern.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("rule needs <pattern>=<value>: {rule}")),
+    }
+}
+
+enum Transport {
+    Grpc(MetricsServiceClient<ChannelType>),
+    Http {
+        client: reqwest::Client,
+        url: String,
+        json: bool,
+    },
+}
+
+struct ExportFailure {
+    retryable: bool,
+    message: String,
+}
+
+#[derive(Debug, Clone)]
+struct OtelConfig {
+    pub tdigest_quantiles: Vec<f64>,
+    pub tdigest_histogram_bounds: Vec<f64>,
+    pub exponential_histograms: bool,
+    pub batch_window: Duration,
+    pub max_data_points_per_export: usize,
+    pub headers: Vec<(AsciiMetadataKey, AsciiMetadataValue)>,
+    pub max_retries: u32,
+    pub name_format: String,
+    pub kind_rules: Vec<KindRule>,
+    pub unit: String,
+    pub unit_rules: Vec<UnitRule>,
+}
+
 pub struct OtelSender {
     rx: MetricsReceiveQueue,
-    client: MetricsServiceClient<ChannelType>,
+    transport: Transport,
+    configuration: OtelConfig,
+    resource: Resource,
+    cumulative: Option<CumulativeStage>,
 }
 
 impl OtelSender {
     pub async fn new_connection(
         opentelemetry_endpoint: &str,
         rx: MetricsReceiveQueue,
-        insecure: bool,
+        options: Options,
     ) -> Result<OtelSender, SinkError> {
-        let client = match get_channel(opentelemetry_endpoint, insecure).await {
-            Ok(channel) => MetricsServiceClient::new(channel),
-            Err(e) => {
+        let client_certificate = match (
+            &options.otlp_client_certificate,
+            &options.otlp_client_private_key,
+        ) {
+            (Some(certificate), Some(private_key)) => Some(ClientCertificate {
+                certificate_pem: tokio::fs::read(certificate).await.map_err(|e| {
+                    SinkError::other("could not read otlp client certificate", Box::new(e))
+                })?,
+                private_key_pem: tokio::fs::read(private_key).await.map_err(|e| {
+                    SinkError::other("could not read otlp client private key", Box::new(e))
+                })?,
+            }),
+            (None, None) => None,
+            _ => {
                 return Err(SinkError::StringError(StringError {
-                    message: format!("Could not get an otel channel: {:?}", e),
+                    message: "otlp client certificate and private key go together".to_string(),
                 }))
             }
         };
+        let mut headers = Vec::with_capacity(options.otlp_header.len());
+        for header in &options.otlp_header {
+            let (name, value) = header.split_once('=').ok_or_else(|| {
+                SinkError::StringError(StringError {
+                    message: format!("otlp header needs <name>=<value>: {header}"),
+                })
+            })?;
+            headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+        }
+
+        let transport = match options.otlp_protocol {
+            OtlpProtocol::Grpc => {
+                match get_channel_with_client_certificate(
+                    opentelemetry_endpoint,
+                    options.otlp_insecure,
+                    client_certificate,
+                )
+                .await
+                {
+                    Ok(channel) => Transport::Grpc(MetricsServiceClient::new(channel)),
+                    Err(e) => {
+                        return Err(SinkError::StringError(StringError {
+                            message: format!("Could not get an otel channel: {:?}", e),
+                        }))
+                    }
+                }
+            }
+            OtlpProtocol::HttpProtobuf | OtlpProtocol::HttpJson => {
+                let mut header_map = HeaderMap::new();
+                for (name, value) in &headers {
+                    header_map.insert(
+                        HeaderName::try_from(name)
+                            .map_err(|e| SinkError::other("bad otlp header name", Box::new(e)))?,
+                        HeaderValue::try_from(value)
+                            .map_err(|e| SinkError::other("bad otlp header value", Box::new(e)))?,
+                    );
+                }
+                let mut client = reqwest::Client::builder()
+                    .default_headers(header_map)
+                    .danger_accept_invalid_certs(options.otlp_insecure)
+                    .timeout(Duration::from_secs(30));
+                if let Some(client_certificate) = client_certificate {
+                    client = client.identity(
+                        reqwest::Identity::from_pem(
+                            &[
+                                client_certificate.certificate_pem,
+                                client_certificate.private_key_pem,
+                            ]
+                            .concat(),
+                        )
+                        .map_err(|e| {
+                            SinkError::other("bad otlp client certificate", Box::new(e))
+                        })?,
+                    );
+                }
+                let endpoint = opentelemetry_endpoint.trim_end_matches('/');
+                Transport::Http {
+                    client: client.build().map_err(|e| {
+                        SinkError::other("could not build otlp http client", Box::new(e))
+                    })?,
+                    url: if endpoint.ends_with("/v1/metrics") {
+                        endpoint.to_string()
+                    } else {
+                        format!("{endpoint}/v1/metrics")
+                    },
+                    json: options.otlp_protocol == OtlpProtocol::HttpJson,
+                }
+            }
+        };
+        let mut grpc_headers = Vec::with_capacity(headers.len());
+        for (name, value) in &headers {
+            grpc_headers.push((
+                AsciiMetadataKey::from_bytes(name.as_bytes())
+                    .map_err(|e| SinkError::other("bad otlp header name", Box::new(e)))?,
+                AsciiMetadataValue::try_from(value.as_str())
+                    .map_err(|e| SinkError::other("bad otlp header value", Box::new(e)))?,
+            ));
+        }
+        let resource = Resource {
+            attributes: resource_attributes(&options)?,
+            dropped_attributes_count: 0,
+        };
+        let mut tdigest_histogram_bounds = options.otlp_tdigest_histogram_bounds;
+        tdigest_histogram_bounds.sort_by(f64::total_cmp);
+        tdigest_histogram_bounds.dedup();
 
-        Ok(OtelSender { rx, client })
+        Ok(OtelSender {
+            rx,
+            transport,
+            configuration: OtelConfig {
+                tdigest_quantiles: options.otlp_tdigest_quantiles,
+                tdigest_histogram_bounds,
+                exponential_histograms: options.otlp_exponential_histograms,
+                batch_window: options.otlp_batch_window,
+                max_data_points_per_export: options.otlp_max_data_points_per_export.max(1),
+                headers: grpc_headers,
+                max_retries: options.otlp_max_retries,
+                name_format: options.otlp_name_format,
+                kind_rules: options.otlp_kind_rule,
+                unit: options.otlp_unit,
+                unit_rules: options.otlp_unit_rule,
+            },
+            resource,
+            cumulative: options
+                .otlp_cumulative
+                .then(|| CumulativeStage::new(options.otlp_cumulative_series_expiry)),
+        })
     }
 
     pub async fn consume_stuff(mut self) -> Result<u32, SinkError> {
@@ -45,121 +270,501 @@ impl OtelSender {
 
         while let Some(mut batch) = self.rx.recv().await {
             log::info!("Sender woke. Trying to collect a batch...");
-            sleep(Duration::from_secs(5)).await;
 
-            let deadline = Instant::now() + Duration::from_secs(1);
+            let deadline = Instant::now() + self.configuration.batch_window;
             let mut api_calls: u32 = 1;
             while let Ok(Some(mut extras)) = timeout_at(deadline, self.rx.recv()).await {
                 api_calls += 1;
                 batch.append(&mut extras);
             }
-            let export_metrics: Vec<opentelemetry_metrics::Metric> = batch.into_iter()
+            let export_metrics: Vec<opentelemetry_metrics::Metric> = batch
+                .into_iter()
                 .flat_map(|datum| {
-                    let dimensions: Vec<KeyValue> = datum.dimensions.into_iter()
+                    let dimensions: Vec<KeyValue> = datum
+                        .dimensions
+                        .into_iter()
                         .filter_map(|(name, dimension)| {
-                            dimension.value.map(|value| {
-                                KeyValue {
-                                    key: name,
-                                    value: Some(AnyValue { value: Some(match value {
-                                        goodmetrics::dimension::Value::String(s) => any_value::Value::StringValue(s),
-                                        goodmetrics::dimension::Value::Number(n) => any_value::Value::IntValue(n as i64),
-                                        goodmetrics::dimension::Value::Boolean(b) => any_value::Value::BoolValue(b),
-                                    }) }),
-                                }
+                            dimension.value.map(|value| KeyValue {
+                                key: name,
+                                value: Some(AnyValue {
+                                    value: Some(match value {
+                                        goodmetrics::dimension::Value::String(s) => {
+                                            any_value::Value::StringValue(s)
+                                        }
+                                        goodmetrics::dimension::Value::Number(n) => {
+                                            any_value::Value::IntValue(n as i64)
+                                        }
+                                        goodmetrics::dimension::Value::Boolean(b) => {
+                                            any_value::Value::BoolValue(b)
+                                        }
+                                    }),
+                                }),
                             })
                         })
                         .collect();
-                    datum.measurements.into_iter()
-                        .filter_map(|(name, measurement)| {
-                            // Data::Gauge(()) {
-                            // }
-                            measurement.value.map(|value| {
-                                opentelemetry_metrics::Metric {
-                                    // So yeah, this splays all your metrics across a shared namespace because prometheus / otel.
-                                    name: format!("{metric_name}_{measurement_name}", metric_name = datum.metric, measurement_name=name),
-                                    description: "goodmetrics compatibility conversion".to_string(),
-                                    unit: "1".to_string(),
-                                    data: Some(match value {
-                                        goodmetrics::measurement::Value::I64(i) => opentelemetry_metrics::metric::Data::Gauge(opentelemetry_metrics::Gauge {
-                                            data_points: vec![
-                                                int_data_point(i, datum.unix_nanos, &dimensions),
-                                            ],
-                                        }),
-                                        goodmetrics::measurement::Value::I32(i) => opentelemetry_metrics::metric::Data::Gauge(opentelemetry_metrics::Gauge {
-                                            data_points: vec![
-                                                int_data_point(i as i64, datum.unix_nanos, &dimensions),
-                                            ],
-                                        }),
-                                        goodmetrics::measurement::Value::F64(f) => opentelemetry_metrics::metric::Data::Gauge(opentelemetry_metrics::Gauge {
-                                            data_points: vec![
-                                                float_data_point(f, datum.unix_nanos, &dimensions),
-                                            ],
-                                        }),
-                                        goodmetrics::measurement::Value::F32(f) => opentelemetry_metrics::metric::Data::Gauge(opentelemetry_metrics::Gauge {
-                                            data_points: vec![
-                                                float_data_point(f as f64, datum.unix_nanos, &dimensions),
-                                            ],
-                                        }),
-                                        goodmetrics::measurement::Value::StatisticSet(ss) => opentelemetry_metrics::metric::Data::Summary(opentelemetry_metrics::Summary {
-                                            data_points: vec![
-                                                // Well, this is the closest thing in opentelemetry. Summaries are _terrible_ though because
-                                                // they encourage the incredibly error-prone practice of recording quantiles from the source.
-                                                summary_data_point(ss, datum.unix_nanos, &dimensions),
-                                            ],
-                                        }),
-                                        goodmetrics::measurement::Value::Histogram(h) => opentelemetry_metrics::metric::Data::Histogram(opentelemetry_metrics::Histogram {
-                                            aggregation_temporality: opentelemetry_metrics::AggregationTemporality::Delta as i32,
-                                            data_points: vec![
-                                                // Well, this is the closest thing in opentelemetry. Summaries are _terrible_ though because
-                                                // they encourage the incredibly error-prone practice of recording quantiles from the source.
-                                                histogram_data_point(h, datum.unix_nanos, &dimensions),
-                                            ],
-                                        }),
-                                        goodmetrics::measurement::Value::Tdigest(t) => {
-                                            unimplemented!("tdigest for opentelemetry is not supported: {t:?}")
-                                        },
-                                    }),
-                                }
-                            })
+                    datum
+                        .measurements
+                        .into_iter()
+                        .flat_map(|(name, measurement)| {
+                            self.measurement_metrics(
+                                &datum.metric,
+                                &name,
+                                measurement,
+                                datum.unix_nanos,
+                                &dimensions,
+                            )
                         })
                         .collect::<Vec<opentelemetry_metrics::Metric>>()
                 })
                 .collect();
-            match self
-                .client
-                .export(ExportMetricsServiceRequest {
-                    resource_metrics: vec![opentelemetry_metrics::ResourceMetrics {
-                        resource: None,
-                        schema_url: "".to_string(),
-                        instrumentation_library_metrics: vec![
-                            opentelemetry_metrics::InstrumentationLibraryMetrics {
-                                instrumentation_library: Some(InstrumentationLibrary {
-                                    name: "goodmetrics".to_string(),
-                                    version: "42".to_string(),
-                                }),
-                                schema_url: "".to_string(),
-                                metrics: export_metrics,
-                            },
-                        ],
-                    }],
-                })
-                .await
-            {
-                Ok(response) => {
-                    log::info!(
-                        "Sent {} batched calls to otel. Response: {:?}",
-                        api_calls,
-                        response
-                    );
-                }
-                Err(error) => {
-                    log::error!("Error from otel: {:?}", error);
+            let export_metrics = match &mut self.cumulative {
+                Some(cumulative) => cumulative.accumulate(export_metrics),
+                None => export_metrics,
+            };
+
+            // Backends limit request sizes, so big batches go out in several exports.
+            let mut export = Vec::new();
+            let mut export_data_points = 0;
+            for metric in export_metrics {
+                export_data_points += data_point_count(&metric);
+                export.push(metric);
+                if self.configuration.max_data_points_per_export <= export_data_points {
+                    self.export(std::mem::take(&mut export), api_calls).await;
+                    export_data_points = 0;
                 }
             }
+            if !export.is_empty() {
+                self.export(export, api_calls).await;
+            }
         }
 
         Ok(1)
     }
+
+    async fn export(&mut self, metrics: Vec<opentelemetry_metrics::Metric>, api_calls: u32) {
+        let data_points: usize = metrics.iter().map(data_point_count).sum();
+        let export_request = ExportMetricsServiceRequest {
+            resource_metrics: vec![opentelemetry_metrics::ResourceMetrics {
+                resource: Some(self.resource.clone()),
+                schema_url: "".to_string(),
+                scope_metrics: vec![opentelemetry_metrics::ScopeMetrics {
+                    scope: Some(InstrumentationScope {
+                        name: "goodmetricsd".to_string(),
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                        attributes: vec![],
+                        dropped_attributes_count: 0,
+                    }),
+                    schema_url: "".to_string(),
+                    metrics,
+                }],
+            }],
+        };
+
+        let mut attempt = 0;
+        loop {
+            let result = match &mut self.transport {
+                Transport::Grpc(client) => {
+                    send_grpc(client, &self.configuration.headers, export_request.clone()).await
+                }
+                Transport::Http { client, url, json } => {
+                    send_http(client, url, *json, &export_request).await
+                }
+            };
+            match result {
+                Ok(partial_success) => {
+                    match partial_success {
+                        // Rejected points are not retryable; the server has told us why in the message.
+                        Some(partial) if 0 < partial.rejected_data_points => log::warn!(
+                            "otel rejected {} of {} data points: {}",
+                            partial.rejected_data_points,
+                            data_points,
+                            partial.error_message
+                        ),
+                        Some(partial) if !partial.error_message.is_empty() => {
+                            log::warn!("otel accepted with a warning: {}", partial.error_message)
+                        }
+                        _ => log::info!(
+                            "Sent {} data points from {} batched calls to otel",
+                            data_points,
+                            api_calls
+                        ),
+                    }
+                    return;
+                }
+                Err(failure) => {
+                    if attempt < self.configuration.max_retries && failure.retryable {
+                        let backoff = Duration::from_millis(100 * (1 << attempt.min(6)));
+                        log::warn!(
+                            "otel export failed, retrying in {:?}: {}",
+                            backoff,
+                            failure.message
+                        );
+                        attempt += 1;
+                        sleep(backoff).await;
+                    } else {
+                        log::error!(
+                            "dropping {} data points. Error from otel: {}",
+                            data_points,
+                            failure.message
+                        );
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    fn measurement_metrics(
+        &self,
+        metric_name: &str,
+        measurement_name: &str,
+        measurement: goodmetrics::Measurement,
+        nano_time: u64,
+        dimensions: &[KeyValue],
+    ) -> Vec<opentelemetry_metrics::Metric> {
+        let value = match measurement.value {
+            Some(value) => value,
+            None => return vec![],
+        };
+        // So yeah, this splays all your metrics across a shared namespace because prometheus / otel.
+        let name = self
+            .configuration
+            .name_format
+            .replace("{metric}", metric_name)
+            .replace("{measurement}", measurement_name);
+        let kind = match goodmetrics::MeasurementKind::from_i32(measurement.kind) {
+            Some(goodmetrics::MeasurementKind::Unspecified) | None => self
+                .configuration
+                .kind_rules
+                .iter()
+                .find(|rule| glob_match(&rule.pattern, measurement_name))
+                .map(|rule| rule.kind)
+                .unwrap_or(goodmetrics::MeasurementKind::Gauge),
+            Some(kind) => kind,
+        };
+        let unit = self
+            .configuration
+            .unit_rules
+            .iter()
+            .find(|rule| glob_match(&rule.pattern, measurement_name))
+            .map(|rule| &rule.unit)
+            .unwrap_or(&self.configuration.unit);
+
+        let mut metrics = self.value_metrics(name, value, kind, nano_time, dimensions);
+        for metric in &mut metrics {
+            metric.unit = unit.clone();
+        }
+        metrics
+    }
+
+    fn value_metrics(
+        &self,
+        name: String,
+        value: goodmetrics::measurement::Value,
+        kind: goodmetrics::MeasurementKind,
+        nano_time: u64,
+        dimensions: &[KeyValue],
+    ) -> Vec<opentelemetry_metrics::Metric> {
+        match value {
+            goodmetrics::measurement::Value::I64(i) => vec![metric(
+                name,
+                number_data(kind, int_data_point(i, nano_time, dimensions)),
+            )],
+            goodmetrics::measurement::Value::I32(i) => vec![metric(
+                name,
+                number_data(kind, int_data_point(i as i64, nano_time, dimensions)),
+            )],
+            goodmetrics::measurement::Value::F64(f) => vec![metric(
+                name,
+                number_data(kind, float_data_point(f, nano_time, dimensions)),
+            )],
+            goodmetrics::measurement::Value::F32(f) => vec![metric(
+                name,
+                number_data(kind, float_data_point(f as f64, nano_time, dimensions)),
+            )],
+            goodmetrics::measurement::Value::StatisticSet(ss) => vec![metric(
+                name,
+                opentelemetry_metrics::metric::Data::Summary(opentelemetry_metrics::Summary {
+                    data_points: vec![
+                        // Well, this is the closest thing in opentelemetry. Summaries are _terrible_ though because
+                        // they encourage the incredibly error-prone practice of recording quantiles from the source.
+                        summary_data_point(ss, nano_time, dimensions),
+                    ],
+                }),
+            )],
+            goodmetrics::measurement::Value::Histogram(h) => vec![metric(
+                name,
+                opentelemetry_metrics::metric::Data::Histogram(opentelemetry_metrics::Histogram {
+                    aggregation_temporality: opentelemetry_metrics::AggregationTemporality::Delta
+                        as i32,
+                    data_points: vec![histogram_data_point(h, nano_time, dimensions)],
+                }),
+            )],
+            // Exponential histograms fit t-digests: both follow the data rather than fixed bounds,
+            // and backends can merge them across series and time, which they can't with summaries.
+            goodmetrics::measurement::Value::Tdigest(t)
+                if self.configuration.exponential_histograms =>
+            {
+                vec![metric(
+                    name,
+                    opentelemetry_metrics::metric::Data::ExponentialHistogram(
+                        opentelemetry_metrics::ExponentialHistogram {
+                            aggregation_temporality:
+                                opentelemetry_metrics::AggregationTemporality::Delta as i32,
+                            data_points: vec![tdigest_exponential_histogram_data_point(
+                                &t, nano_time, dimensions,
+                            )],
+                        },
+                    ),
+                )]
+            }
+            goodmetrics::measurement::Value::Tdigest(t) => {
+                let mut metrics = Vec::with_capacity(2);
+                if !self.configuration.tdigest_histogram_bounds.is_empty() {
+                    metrics.push(metric(
+                        format!("{name}_histogram"),
+                        opentelemetry_metrics::metric::Data::Histogram(
+                            opentelemetry_metrics::Histogram {
+                                aggregation_temporality:
+                                    opentelemetry_metrics::AggregationTemporality::Delta as i32,
+                                data_points: vec![tdigest_histogram_data_point(
+                                    &t,
+                                    &self.configuration.tdigest_histogram_bounds,
+                                    nano_time,
+                                    dimensions,
+                                )],
+                            },
+                        ),
+                    ));
+                }
+                metrics.push(metric(
+                    name,
+                    opentelemetry_metrics::metric::Data::Summary(opentelemetry_metrics::Summary {
+                        data_points: vec![tdigest_summary_data_point(
+                            &t,
+                            &self.configuration.tdigest_quantiles,
+                            nano_time,
+                            dimensions,
+                        )],
+                    }),
+                ));
+                metrics
+            }
+        }
+    }
+}
+
+/// service.name, deployment.environment and host.name, then any other key=value attributes.
+fn resource_attributes(options: &Options) -> Result<Vec<KeyValue>, SinkError> {
+    let mut attributes: BTreeMap<String, String> = BTreeMap::new();
+    attributes.insert(
+        "service.name".to_string(),
+        options.otlp_service_name.clone(),
+    );
+    if let Some(environment) = &options.otlp_deployment_environment {
+        attributes.insert("deployment.environment".to_string(), environment.clone());
+    }
+    if let Some(host_name) = &options.otlp_host_name {
+        attributes.insert("host.name".to_string(), host_name.clone());
+    }
+    for attribute in &options.otlp_resource_attribute {
+        let (key, value) = attribute.split_once('=').ok_or_else(|| {
+            SinkError::StringError(StringError {
+                message: format!("otlp resource attribute needs <key>=<value>: {attribute}"),
+            })
+        })?;
+        attributes.insert(key.trim().to_string(), value.trim().to_string());
+    }
+
+    Ok(attributes
+        .into_iter()
+        .map(|(key, value)| KeyValue {
+            key,
+            value: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(value)),
+            }),
+        })
+        .collect())
+}
+
+async fn send_grpc(
+    client: &mut MetricsServiceClient<ChannelType>,
+    headers: &[(AsciiMetadataKey, AsciiMetadataValue)],
+    export_request: ExportMetricsServiceRequest,
+) -> Result<Option<ExportMetricsPartialSuccess>, ExportFailure> {
+    let mut request = tonic::Request::new(export_request);
+    for (name, value) in headers {
+        request.metadata_mut().insert(name.clone(), value.clone());
+    }
+    client
+        .export(request)
+        .await
+        .map(|response| response.into_inner().partial_success)
+        .map_err(|status| ExportFailure {
+            retryable: is_retryable(status.code()),
+            message: format!("{status:?}"),
+        })
+}
+
+async fn send_http(
+    client: &reqwest::Client,
+    url: &str,
+    json: bool,
+    export_request: &ExportMetricsServiceRequest,
+) -> Result<Option<ExportMetricsPartialSuccess>, ExportFailure> {
+    let (content_type, body) = if json {
+        (
+            "application/json",
+            serde_json::to_vec(&opentelemetry_json::export_request(export_request)).map_err(
+                |e| ExportFailure {
+                    retryable: false,
+                    message: format!("could not encode json: {e:?}"),
+                },
+            )?,
+        )
+    } else {
+        ("application/x-protobuf", export_request.encode_to_vec())
+    };
+    let response = client
+        .post(url)
+        .header("content-type", content_type)
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| ExportFailure {
+            retryable: true,
+            message: format!("{e:?}"),
+        })?;
+    let status = response.status();
+    let body = response.bytes().await.map_err(|e| ExportFailure {
+        retryable: true,
+        message: format!("{e:?}"),
+    })?;
+    if !status.is_success() {
+        return Err(ExportFailure {
+            // The statuses otlp/http says a client should retry
+            retryable: matches!(
+                status,
+                StatusCode::TOO_MANY_REQUESTS
+                    | StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+                    | StatusCode::GATEWAY_TIMEOUT
+            ),
+            message: format!("{status}: {}", String::from_utf8_lossy(&body)),
+        });
+    }
+
+    if json {
+        Ok(serde_json::from_slice::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|response| json_partial_success(&response)))
+    } else {
+        Ok(ExportMetricsServiceResponse::decode(body)
+            .ok()
+            .and_then(|response| response.partial_success))
+    }
+}
+
+fn json_partial_success(response: &serde_json::Value) -> Option<ExportMetricsPartialSuccess> {
+    let partial = response.get("partialSuccess")?;
+    Some(ExportMetricsPartialSuccess {
+        // 64 bit integers may be strings in protobuf json
+        rejected_data_points: match partial.get("rejectedDataPoints") {
+            Some(serde_json::Value::String(s)) => s.parse().unwrap_or_default(),
+            Some(n) => n.as_i64().unwrap_or_default(),
+            None => 0,
+        },
+        error_message: partial
+            .get("errorMessage")
+            .and_then(|message| message.as_str())
+            .unwrap_or_default()
+            .to_string(),
+    })
+}
+
+/// The codes otlp says a client should retry
+fn is_retryable(code: Code) -> bool {
+    matches!(
+        code,
+        Code::Cancelled
+            | Code::DeadlineExceeded
+            | Code::ResourceExhausted
+            | Code::Aborted
+            | Code::OutOfRange
+            | Code::Unavailable
+            | Code::DataLoss
+    )
+}
+
+fn data_point_count(metric: &opentelemetry_metrics::Metric) -> usize {
+    match &metric.data {
+        Some(opentelemetry_metrics::metric::Data::Gauge(g)) => g.data_points.len(),
+        Some(opentelemetry_metrics::metric::Data::Sum(s)) => s.data_points.len(),
+        Some(opentelemetry_metrics::metric::Data::Histogram(h)) => h.data_points.len(),
+        Some(opentelemetry_metrics::metric::Data::ExponentialHistogram(h)) => h.data_points.len(),
+        Some(opentelemetry_metrics::metric::Data::Summary(s)) => s.data_points.len(),
+        None => 0,
+    }
+}
+
+fn number_data(
+    kind: goodmetrics::MeasurementKind,
+    data_point: opentelemetry_metrics::NumberDataPoint,
+) -> opentelemetry_metrics::metric::Data {
+    let (temporality, is_monotonic) = match kind {
+        goodmetrics::MeasurementKind::Unspecified | goodmetrics::MeasurementKind::Gauge => {
+            return opentelemetry_metrics::metric::Data::Gauge(opentelemetry_metrics::Gauge {
+                data_points: vec![data_point],
+            })
+        }
+        goodmetrics::MeasurementKind::DeltaSum => {
+            (opentelemetry_metrics::AggregationTemporality::Delta, false)
+        }
+        goodmetrics::MeasurementKind::CumulativeSum => (
+            opentelemetry_metrics::AggregationTemporality::Cumulative,
+            false,
+        ),
+        goodmetrics::MeasurementKind::MonotonicDeltaSum => {
+            (opentelemetry_metrics::AggregationTemporality::Delta, true)
+        }
+        goodmetrics::MeasurementKind::MonotonicCumulativeSum => (
+            opentelemetry_metrics::AggregationTemporality::Cumulative,
+            true,
+        ),
+    };
+    opentelemetry_metrics::metric::Data::Sum(opentelemetry_metrics::Sum {
+        data_points: vec![data_point],
+        aggregation_temporality: temporality as i32,
+        is_monotonic,
+    })
+}
+
+/// * matches any run of characters, anywhere in the pattern.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let name = match name.strip_prefix(prefix) {
+                Some(name) => name,
+                None => return false,
+            };
+            (0..=name.len())
+                .filter(|i| name.is_char_boundary(*i))
+                .any(|i| glob_match(rest, &name[i..]))
+        }
+    }
+}
+
+fn metric(
+    name: String,
+    data: opentelemetry_metrics::metric::Data,
+) -> opentelemetry_metrics::Metric {
+    opentelemetry_metrics::Metric {
+        name,
+        description: "goodmetrics compatibility conversion".to_string(),
+        unit: "1".to_string(),
+        data: Some(data),
+    }
 }
 
 fn int_data_point(
@@ -217,6 +822,8 @@ fn summary_data_point(
     }
 }
 
+/// goodmetrics buckets are keyed by their inclusive upper bound, which is what otlp's explicit
+/// bounds are. Nothing can be above the highest key, so otlp's overflow bucket is always empty.
 fn histogram_data_point(
     h: goodmetrics::Histogram,
     nano_time: u64,
@@ -231,13 +838,271 @@ fn histogram_data_point(
         flags: 0,
         count: buckets.values().sum(),
 
-        // Sum is not faithfully maintained in goodmetrics. It's approximate, and over-estimated.
-        sum: buckets
-            .iter()
-            .map(|(bucket, count)| (bucket * *count as i64) as f64)
-            .sum(),
+        // goodmetrics histograms don't carry a sum, and bucket bounds are not values.
+        sum: None,
+        min: None,
+        max: None,
 
-        bucket_counts: buckets.values().copied().collect(),
+        bucket_counts: buckets
+            .values()
+            .copied()
+            .chain(std::iter::once(0))
+            .collect(),
         explicit_bounds: buckets.keys().map(|bucket| *bucket as f64).collect(),
     }
 }
+
+fn tdigest_summary_data_point(
+    t: &goodmetrics::TDigest,
+    quantiles: &[f64],
+    nano_time: u64,
+    dimensions: &[KeyValue],
+) -> opentelemetry_metrics::SummaryDataPoint {
+    let centroids = sorted_centroids(t);
+    opentelemetry_metrics::SummaryDataPoint {
+        attributes: dimensions.to_owned(),
+        start_time_unix_nano: 0,
+        time_unix_nano: nano_time,
+        flags: 0,
+        count: t.count,
+        sum: t.sum,
+        // min and max ride along as the 0 and 1 quantiles, like statistic sets.
+        quantile_values: std::iter::once(0_f64)
+            .chain(quantiles.iter().copied().filter(|q| 0.0 < *q && *q < 1.0))
+            .chain(std::iter::once(1_f64))
+            .map(
+                |quantile| opentelemetry_metrics::summary_data_point::ValueAtQuantile {
+                    quantile,
+                    value: tdigest_quantile(&centroids, t.min, t.max, quantile),
+                },
+            )
+            .collect(),
+    }
+}
+
+/// Each centroid's weight lands in the bucket its mean falls in. It's an approximation; the
+/// summary's quantiles are closer to the truth.
+fn tdigest_histogram_data_point(
+    t: &goodmetrics::TDigest,
+    bounds: &[f64],
+    nano_time: u64,
+    dimensions: &[KeyValue],
+) -> opentelemetry_metrics::HistogramDataPoint {
+    // Bucket i holds (bounds[i-1], bounds[i]], and the last bucket holds everything above the last bound.
+    let mut bucket_counts = vec![0; bounds.len() + 1];
+    for centroid in &t.centroids {
+        bucket_counts[bounds.partition_point(|bound| *bound < centroid.mean)] += centroid.weight;
+    }
+    opentelemetry_metrics::HistogramDataPoint {
+        attributes: dimensions.to_owned(),
+        start_time_unix_nano: 0,
+        time_unix_nano: nano_time,
+        exemplars: vec![],
+        flags: 0,
+        count: bucket_counts.iter().sum(),
+        sum: non_negative_sum(t),
+        min: Some(t.min),
+        max: Some(t.max),
+        bucket_counts,
+        explicit_bounds: bounds.to_vec(),
+    }
+}
+
+/// Each centroid's weight lands in the bucket its mean falls in, at the finest scale that keeps
+/// each range within MAX_EXPONENTIAL_BUCKETS.
+fn tdigest_exponential_histogram_data_point(
+    t: &goodmetrics::TDigest,
+    nano_time: u64,
+    dimensions: &[KeyValue],
+) -> opentelemetry_metrics::ExponentialHistogramDataPoint {
+    let centroids = sorted_centroids(t);
+    let positive: Vec<(f64, f64)> = centroids
+        .iter()
+        .copied()
+        .filter(|(mean, _)| 0.0 < *mean)
+        .collect();
+    let negative: Vec<(f64, f64)> = centroids
+        .iter()
+        .filter(|(mean, _)| *mean < 0.0)
+        .map(|(mean, weight)| (-mean, *weight))
+        .collect();
+    let zero_count = centroids
+        .iter()
+        .filter(|(mean, _)| *mean == 0.0)
+        .map(|(_, weight)| *weight as u64)
+        .sum();
+
+    let scale = (MIN_EXPONENTIAL_SCALE..=MAX_EXPONENTIAL_SCALE)
+        .rev()
+        .find(|scale| {
+            [&positive, &negative]
+                .iter()
+                .all(|range| match (range.first(), range.last()) {
+                    (Some((low, _)), Some((high, _))) => {
+                        exponential_index(*high, *scale) - exponential_index(*low, *scale)
+                            < MAX_EXPONENTIAL_BUCKETS
+                    }
+                    _ => true,
+                })
+        })
+        .unwrap_or(MIN_EXPONENTIAL_SCALE);
+    let positive = exponential_buckets(&positive, scale);
+    let negative = exponential_buckets(&negative, scale);
+
+    opentelemetry_metrics::ExponentialHistogramDataPoint {
+        attributes: dimensions.to_owned(),
+        start_time_unix_nano: 0,
+        time_unix_nano: nano_time,
+        exemplars: vec![],
+        flags: 0,
+        count: zero_count
+            + positive.bucket_counts.iter().sum::<u64>()
+            + negative.bucket_counts.iter().sum::<u64>(),
+        sum: non_negative_sum(t),
+        min: Some(t.min),
+        max: Some(t.max),
+        scale,
+        zero_count,
+        zero_threshold: 0.0,
+        positive: Some(positive),
+        negative: Some(negative),
+    }
+}
+
+const MIN_EXPONENTIAL_SCALE: i32 = -10;
+const MAX_EXPONENTIAL_SCALE: i32 = 20;
+/// The opentelemetry sdks' default maximum size
+pub const MAX_EXPONENTIAL_BUCKETS: i32 = 160;
+
+/// Bucket index holds (base^index, base^(index+1)], base = 2^(2^-scale)
+fn exponential_index(magnitude: f64, scale: i32) -> i32 {
+    (magnitude.log2() * 2_f64.powi(scale)).ceil() as i32 - 1
+}
+
+/// magnitudes must be sorted and positive
+fn exponential_buckets(
+    magnitudes: &[(f64, f64)],
+    scale: i32,
+) -> opentelemetry_metrics::exponential_histogram_data_point::Buckets {
+    let offset = match magnitudes.first() {
+        Some((low, _)) => exponential_index(*low, scale),
+        None => 0,
+    };
+    let mut bucket_counts: Vec<u64> = Vec::new();
+    for (magnitude, weight) in magnitudes {
+        let i = (exponential_index(*magnitude, scale) - offset) as usize;
+        if bucket_counts.len() <= i {
+            bucket_counts.resize(i + 1, 0);
+        }
+        bucket_counts[i] += *weight as u64;
+    }
+    opentelemetry_metrics::exponential_histogram_data_point::Buckets {
+        offset,
+        bucket_counts,
+    }
+}
+
+/// Histogram sums are only for non-negative populations, per OpenMetrics.
+fn non_negative_sum(t: &goodmetrics::TDigest) -> Option<f64> {
+    (0.0 <= t.min).then_some(t.sum)
+}
+
+fn sorted_centroids(t: &goodmetrics::TDigest) -> Vec<(f64, f64)> {
+    let mut centroids: Vec<(f64, f64)> = t
+        .centroids
+        .iter()
+        .filter(|c| 0 < c.weight)
+        .map(|c| (c.mean, c.weight as f64))
+        .collect();
+    centroids.sort_by(|a, b| a.0.total_cmp(&b.0));
+    centroids
+}
+
+/// Interpolates between centroid centers, with min and max pinning the tails.
+fn tdigest_quantile(centroids: &[(f64, f64)], min: f64, max: f64, quantile: f64) -> f64 {
+    let total: f64 = centroids.iter().map(|(_, weight)| weight).sum();
+    if centroids.is_empty() || total == 0.0 {
+        return f64::NAN;
+    }
+    if quantile <= 0.0 {
+        return min;
+    }
+    if 1.0 <= quantile {
+        return max;
+    }
+
+    let target = quantile * total;
+    let (mut previous_rank, mut previous_value) = (0.0, min);
+    let mut seen = 0.0;
+    for (mean, weight) in centroids {
+        let rank = seen + weight / 2.0;
+        if target < rank {
+            return previous_value
+                + (mean - previous_value) * (target - previous_rank) / (rank - previous_rank);
+        }
+        seen += weight;
+        (previous_rank, previous_value) = (rank, *mean);
+    }
+    if total <= previous_rank {
+        return max;
+    }
+    previous_value + (max - previous_value) * (target - previous_rank) / (total - previous_rank)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use communication::proto::goodmetrics;
+
+    use super::histogram_data_point;
+
+    fn histogram(buckets: &[(i64, u64)]) -> goodmetrics::Histogram {
+        goodmetrics::Histogram {
+            buckets: buckets.iter().copied().collect::<HashMap<i64, u64>>(),
+        }
+    }
+
+    #[test]
+    fn histogram_has_one_more_bucket_than_bounds() {
+        let point = histogram_data_point(histogram(&[(10, 1), (1, 4), (100, 2)]), 42, &[]);
+
+        assert_eq!(point.explicit_bounds, vec![1.0, 10.0, 100.0]);
+        assert_eq!(point.bucket_counts.len(), point.explicit_bounds.len() + 1);
+        assert_eq!(point.bucket_counts, vec![4, 1, 2, 0]);
+    }
+
+    #[test]
+    fn histogram_count_is_the_sum_of_buckets() {
+        let point = histogram_data_point(histogram(&[(5, 3), (50, 7)]), 42, &[]);
+
+        assert_eq!(point.count, 10);
+        assert_eq!(point.count, point.bucket_counts.iter().sum::<u64>());
+        assert_eq!(point.time_unix_nano, 42);
+    }
+
+    #[test]
+    fn histogram_overflow_bucket_is_empty() {
+        let point = histogram_data_point(histogram(&[(5, 3), (50, 7)]), 42, &[]);
+
+        assert_eq!(point.bucket_counts.last(), Some(&0));
+    }
+
+    #[test]
+    fn histogram_has_no_sum() {
+        let point = histogram_data_point(histogram(&[(5, 3)]), 42, &[]);
+
+        assert_eq!(point.sum, None);
+        assert_eq!(point.min, None);
+        assert_eq!(point.max, None);
+    }
+
+    #[test]
+    fn empty_histogram_is_just_the_overflow_bucket() {
+        let point = histogram_data_point(histogram(&[]), 42, &[]);
+
+        assert!(point.explicit_bounds.is_empty());
+        assert_eq!(point.bucket_counts, vec![0]);
+        assert_eq!(point.count, 0);
+    }
+}
@@ -0,0 +1,464 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use arrow::{
+    array::{
+        ArrayRef, BooleanArray, Float32Array, Float64Array, Int32Array, Int64Array, ListArray,
+        MapArray, StringArray, StructArray, TimestampNanosecondArray, UInt64Array,
+    },
+    buffer::{NullBuffer, OffsetBuffer, ScalarBuffer},
+    datatypes::{DataType, Field, Fields, Schema, TimeUnit},
+    record_batch::RecordBatch,
+};
+use communication::proto::goodmetrics::{dimension, measurement, Datum, StatisticSet, TDigest};
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use postgres_types::Type;
+use tokio::time::{timeout_at, Instant};
+
+use crate::{
+    config::options::Options,
+    ingest::ingest_stats::now_nanos,
+    postgres_things::{ddl::clean_id, type_conversion::TypeConverter},
+};
+
+use super::{group_metrics, metricssendqueue::MetricsReceiveQueue, sink_error::SinkError};
+
+/// Writes `<directory>/<metric>/<yyyy-mm-dd>/<hh>/<unix nanos>.parquet`, 1 file per metric-hour per batch.
+/// The columns are the same wide schema the postgres sink makes: time, then a column per dimension and measurement.
+pub struct ParquetSender {
+    rx: MetricsReceiveQueue,
+    directory: PathBuf,
+    batch_window: Duration,
+    type_converter: TypeConverter,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ColumnKind {
+    Text,
+    Boolean,
+    Int64,
+    Int32,
+    Float64,
+    Float32,
+    StatisticSet,
+    Histogram,
+    TDigest,
+}
+
+impl ParquetSender {
+    pub fn new(rx: MetricsReceiveQueue, options: Options) -> ParquetSender {
+        ParquetSender {
+            rx,
+            directory: PathBuf::from(options.parquet_directory.unwrap_or_default()),
+            batch_window: options.parquet_batch_window,
+            type_converter: TypeConverter::without_database(),
+        }
+    }
+
+    pub async fn consume_stuff(mut self) -> Result<u32, SinkError> {
+        log::info!("started parquet consumer in {:?}", self.directory);
+
+        while let Some(mut batch) = self.rx.recv().await {
+            log::info!("Sender woke. Trying to collect a batch...");
+
+            // Parquet files can't be appended to, so a longer window means fewer, bigger files.
+            let deadline = Instant::now() + self.batch_window;
+            while let Ok(Some(mut extras)) = timeout_at(deadline, self.rx.recv()).await {
+                batch.append(&mut extras);
+            }
+
+            let file_name = format!("{}.parquet", now_nanos());
+            for (metric, datums) in group_metrics(batch) {
+                for ((day, hour), datums) in group_hours(datums) {
+                    let partition = self.directory.join(clean_id(&metric)).join(day).join(hour);
+                    match self.write_file(&partition, &file_name, &datums) {
+                        Ok(()) => log::info!("wrote {} rows to {:?}", datums.len(), partition),
+                        Err(e) => log::error!(
+                            "dropping {} rows for {:?}: {:?}",
+                            datums.len(),
+                            partition,
+                            e
+                        ),
+                    }
+                }
+            }
+        }
+
+        log::info!("ended parquet consumer");
+        Ok(1)
+    }
+
+    fn write_file(
+        &self,
+        partition: &Path,
+        file_name: &str,
+        datums: &[Datum],
+    ) -> Result<(), SinkError> {
+        let record_batch = self.record_batch(datums)?;
+
+        std::fs::create_dir_all(partition)
+            .map_err(|e| SinkError::other("could not create partition directory", Box::new(e)))?;
+        // Write next to the final name so readers never see a half-written file.
+        let in_progress = partition.join(format!("{file_name}.tmp"));
+        let file = std::fs::File::create(&in_progress)
+            .map_err(|e| SinkError::other("could not create parquet file", Box::new(e)))?;
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let mut writer = ArrowWriter::try_new(file, record_batch.schema(), Some(properties))
+            .map_err(|e| SinkError::other("could not start parquet file", Box::new(e)))?;
+        writer
+            .write(&record_batch)
+            .map_err(|e| SinkError::other("could not write parquet rows", Box::new(e)))?;
+        writer
+            .close()
+            .map_err(|e| SinkError::other("could not finish parquet file", Box::new(e)))?;
+        std::fs::rename(&in_progress, partition.join(file_name))
+            .map_err(|e| SinkError::other("could not publish parquet file", Box::new(e)))?;
+
+        Ok(())
+    }
+
+    fn record_batch(&self, datums: &[Datum]) -> Result<RecordBatch, SinkError> {
+        let dimension_types = self.type_converter.get_dimension_type_map(datums);
+        let measurement_types = self.type_converter.get_measurement_type_map(datums);
+
+        let mut fields = vec![Field::new(
+            "time",
+            DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into())),
+            false,
+        )];
+        let mut columns: Vec<ArrayRef> = vec![Arc::new(
+            TimestampNanosecondArray::from(
+                datums
+                    .iter()
+                    .map(|d| d.unix_nanos as i64)
+                    .collect::<Vec<_>>(),
+            )
+            .with_timezone("UTC"),
+        )];
+
+        for (name, sql_type) in &dimension_types {
+            let kind = match self.column_kind(sql_type) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            let values = datums
+                .iter()
+                .map(|d| d.dimensions.get(name).and_then(|dim| dim.value.as_ref()));
+            let column: ArrayRef = match kind {
+                ColumnKind::Text => Arc::new(StringArray::from(
+                    values
+                        .map(|v| match v {
+                            Some(dimension::Value::String(s)) => Some(s.as_str()),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>(),
+                )),
+                ColumnKind::Int64 => Arc::new(Int64Array::from(
+                    values
+                        .map(|v| match v {
+                            Some(dimension::Value::Number(n)) => Some(*n as i64),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>(),
+                )),
+                ColumnKind::Boolean => Arc::new(BooleanArray::from(
+                    values
+                        .map(|v| match v {
+                            Some(dimension::Value::Boolean(b)) => Some(*b),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>(),
+                )),
+                _ => continue,
+            };
+            fields.push(Field::new(clean_id(name), column.data_type().clone(), true));
+            columns.push(column);
+        }
+
+        for (name, sql_type) in &measurement_types {
+            let kind = match self.column_kind(sql_type) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            let values: Vec<Option<&measurement::Value>> = datums
+                .iter()
+                .map(|d| d.measurements.get(name).and_then(|m| m.value.as_ref()))
+                .collect();
+            let column = measurement_column(kind, &values)?;
+            fields.push(Field::new(clean_id(name), column.data_type().clone(), true));
+            columns.push(column);
+        }
+
+        RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+            .map_err(|e| SinkError::other("could not assemble parquet rows", Box::new(e)))
+    }
+
+    fn column_kind(&self, sql_type: &Type) -> Option<ColumnKind> {
+        Some(match sql_type {
+            t if *t == Type::TEXT => ColumnKind::Text,
+            t if *t == Type::BOOL => ColumnKind::Boolean,
+            t if *t == Type::INT8 => ColumnKind::Int64,
+            t if *t == Type::INT4 => ColumnKind::Int32,
+            t if *t == Type::FLOAT8 => ColumnKind::Float64,
+            t if *t == Type::FLOAT4 => ColumnKind::Float32,
+            t if *t == Type::JSONB || *t == self.type_converter.histogram_type => {
+                ColumnKind::Histogram
+            }
+            t if *t == self.type_converter.statistic_set_type => ColumnKind::StatisticSet,
+            t if *t == self.type_converter.tdigest_type => ColumnKind::TDigest,
+            _ => return None,
+        })
+    }
+}
+
+fn measurement_column(
+    kind: ColumnKind,
+    values: &[Option<&measurement::Value>],
+) -> Result<ArrayRef, SinkError> {
+    let column: ArrayRef = match kind {
+        ColumnKind::Int64 => Arc::new(Int64Array::from(
+            values
+                .iter()
+                .map(|v| match v {
+                    Some(measurement::Value::I64(i)) => Some(*i),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+        )),
+        ColumnKind::Int32 => Arc::new(Int32Array::from(
+            values
+                .iter()
+                .map(|v| match v {
+                    Some(measurement::Value::I32(i)) => Some(*i),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+        )),
+        ColumnKind::Float64 => Arc::new(Float64Array::from(
+            values
+                .iter()
+                .map(|v| match v {
+                    Some(measurement::Value::F64(f)) => Some(*f),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+        )),
+        ColumnKind::Float32 => Arc::new(Float32Array::from(
+            values
+                .iter()
+                .map(|v| match v {
+                    Some(measurement::Value::F32(f)) => Some(*f),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+        )),
+        ColumnKind::StatisticSet => {
+            let sets: Vec<_> = values
+                .iter()
+                .map(|v| match v {
+                    Some(measurement::Value::StatisticSet(s)) => Some(s),
+                    _ => None,
+                })
+                .collect();
+            let field = |f: fn(&StatisticSet) -> f64| {
+                Arc::new(Float64Array::from(
+                    sets.iter()
+                        .map(|s| s.map(f).unwrap_or_default())
+                        .collect::<Vec<_>>(),
+                )) as ArrayRef
+            };
+            let columns = vec![
+                field(|s| s.minimum),
+                field(|s| s.maximum),
+                field(|s| s.samplesum),
+                Arc::new(UInt64Array::from(
+                    sets.iter()
+                        .map(|s| s.map(|s| s.samplecount).unwrap_or_default())
+                        .collect::<Vec<_>>(),
+                )) as ArrayRef,
+            ];
+            let nulls = NullBuffer::from(sets.iter().map(Option::is_some).collect::<Vec<_>>());
+            Arc::new(
+                StructArray::try_new(statistic_set_fields(), columns, Some(nulls))
+                    .map_err(|e| SinkError::other("bad statistic_set column", Box::new(e)))?,
+            )
+        }
+        ColumnKind::Histogram => {
+            let histograms: Vec<_> = values
+                .iter()
+                .map(|v| match v {
+                    Some(measurement::Value::Histogram(h)) => Some(h),
+                    _ => None,
+                })
+                .collect();
+            let mut offsets = vec![0_i32];
+            let mut keys: Vec<i64> = Vec::new();
+            let mut counts: Vec<u64> = Vec::new();
+            for histogram in &histograms {
+                if let Some(histogram) = histogram {
+                    let buckets: BTreeMap<i64, u64> =
+                        histogram.buckets.iter().map(|(k, v)| (*k, *v)).collect();
+                    keys.extend(buckets.keys().copied());
+                    counts.extend(buckets.values().copied());
+                }
+                offsets.push(keys.len() as i32);
+            }
+            let entries = StructArray::try_new(
+                histogram_entry_fields(),
+                vec![
+                    Arc::new(Int64Array::from(keys)) as ArrayRef,
+                    Arc::new(UInt64Array::from(counts)) as ArrayRef,
+                ],
+                None,
+            )
+            .map_err(|e| SinkError::other("bad histogram entries", Box::new(e)))?;
+            let nulls =
+                NullBuffer::from(histograms.iter().map(Option::is_some).collect::<Vec<_>>());
+            Arc::new(
+                MapArray::try_new(
+                    histogram_entries_field(),
+                    OffsetBuffer::new(ScalarBuffer::from(offsets)),
+                    entries,
+                    Some(nulls),
+                    true,
+                )
+                .map_err(|e| SinkError::other("bad histogram column", Box::new(e)))?,
+            )
+        }
+        ColumnKind::TDigest => {
+            let digests: Vec<_> = values
+                .iter()
+                .map(|v| match v {
+                    Some(measurement::Value::Tdigest(t)) => Some(t),
+                    _ => None,
+                })
+                .collect();
+            let mut offsets = vec![0_i32];
+            let mut means: Vec<f64> = Vec::new();
+            let mut weights: Vec<u64> = Vec::new();
+            for digest in &digests {
+                if let Some(digest) = digest {
+                    means.extend(digest.centroids.iter().map(|c| c.mean));
+                    weights.extend(digest.centroids.iter().map(|c| c.weight));
+                }
+                offsets.push(means.len() as i32);
+            }
+            let centroids = StructArray::try_new(
+                centroid_fields(),
+                vec![
+                    Arc::new(Float64Array::from(means)) as ArrayRef,
+                    Arc::new(UInt64Array::from(weights)) as ArrayRef,
+                ],
+                None,
+            )
+            .map_err(|e| SinkError::other("bad tdigest centroids", Box::new(e)))?;
+            let centroids = ListArray::try_new(
+                centroid_item_field(),
+                OffsetBuffer::new(ScalarBuffer::from(offsets)),
+                Arc::new(centroids),
+                None,
+            )
+            .map_err(|e| SinkError::other("bad tdigest centroid list", Box::new(e)))?;
+            let field = |f: fn(&TDigest) -> f64| {
+                Arc::new(Float64Array::from(
+                    digests
+                        .iter()
+                        .map(|t| t.map(f).unwrap_or_default())
+                        .collect::<Vec<_>>(),
+                )) as ArrayRef
+            };
+            let columns = vec![
+                Arc::new(UInt64Array::from(
+                    digests
+                        .iter()
+                        .map(|t| t.map(|t| t.count).unwrap_or_default())
+                        .collect::<Vec<_>>(),
+                )) as ArrayRef,
+                field(|t| t.sum),
+                field(|t| t.min),
+                field(|t| t.max),
+                Arc::new(centroids) as ArrayRef,
+            ];
+            let nulls = NullBuffer::from(digests.iter().map(Option::is_some).collect::<Vec<_>>());
+            Arc::new(
+                StructArray::try_new(tdigest_fields(), columns, Some(nulls))
+                    .map_err(|e| SinkError::other("bad tdigest column", Box::new(e)))?,
+            )
+        }
+        ColumnKind::Text | ColumnKind::Boolean => {
+            return Err(SinkError::other(
+                "not a measurement column",
+                format!("{kind:?}").into(),
+            ))
+        }
+    };
+    Ok(column)
+}
+
+fn statistic_set_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("minimum", DataType::Float64, false),
+        Field::new("maximum", DataType::Float64, false),
+        Field::new("samplesum", DataType::Float64, false),
+        Field::new("samplecount", DataType::UInt64, false),
+    ])
+}
+
+fn histogram_entry_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("bucket", DataType::Int64, false),
+        Field::new("count", DataType::UInt64, false),
+    ])
+}
+
+fn histogram_entries_field() -> Arc<Field> {
+    Arc::new(Field::new(
+        "entries",
+        DataType::Struct(histogram_entry_fields()),
+        false,
+    ))
+}
+
+fn centroid_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("mean", DataType::Float64, false),
+        Field::new("weight", DataType::UInt64, false),
+    ])
+}
+
+fn centroid_item_field() -> Arc<Field> {
+    Arc::new(Field::new(
+        "item",
+        DataType::Struct(centroid_fields()),
+        false,
+    ))
+}
+
+fn tdigest_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("count", DataType::UInt64, false),
+        Field::new("sum", DataType::Float64, false),
+        Field::new("min", DataType::Float64, false),
+        Field::new("max", DataType::Float64, false),
+        Field::new("centroids", DataType::List(centroid_item_field()), false),
+    ])
+}
+
+// (yyyy-mm-dd, hh) in UTC
+fn group_hours(datums: Vec<Datum>) -> BTreeMap<(String, String), Vec<Datum>> {
+    let mut hours: BTreeMap<(String, String), Vec<Datum>> = BTreeMap::new();
+    for datum in datums {
+        let timestamp =
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_nanos(datum.unix_nanos))
+                .to_string();
+        let key = (timestamp[0..10].to_string(), timestamp[11..13].to_string());
+        hours.entry(key).or_default().push(datum);
+    }
+    hours
+}
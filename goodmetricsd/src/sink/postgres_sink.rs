@@ -1,5 +1,5 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     error::Error,
     pin::pin,
     rc::Rc,
@@ -9,45 +9,98 @@ use std::{
 use crate::{
     config::options::Options,
     postgres_things::{
-        ddl::{self, clean_id},
+        continuous_aggregate::{build_continuous_aggregates, continuous_aggregate_name, Rollup},
+        ddl::{self, postgres_id},
         histogram::{get_or_create_histogram_type, to_jsonmap},
-        postgres_connector::PostgresConnector,
+        postgres_connector::{ConnectionManager, PostgresConnector, PostgresTls},
         statistic_set::get_or_create_statistic_set_type,
+        table_policy::{TablePolicies, TablePolicy},
+        table_schema::TableSchemas,
         tdigest::SqlTdigest,
         type_conversion::TypeConverter,
     },
-    sink::sink_error::{DescribedError, MissingColumn, MissingTable},
+    sink::sink_error::{StaleSchema, TypeConflict},
 };
 use crate::{postgres_things::statistic_set::SqlStatisticSet, sink::sink_error::StringError};
 use bb8::PooledConnection;
-use bb8_postgres::PostgresConnectionManager;
-use communication::proto::goodmetrics::{dimension, measurement, Datum, Dimension, Measurement};
+use clap::ValueEnum;
+use communication::proto::goodmetrics::{dimension, measurement, Datum, Dimension};
+use communication::ClientCertificate;
 use futures::SinkExt;
-use itertools::Itertools;
-use lazy_static::lazy_static;
-use regex::Regex;
+use serde_derive::Deserialize;
 use tokio::{
     task,
     time::{timeout_at, Instant},
 };
 use tokio_postgres::{
+    binary_copy::BinaryCopyInWriter,
     error::SqlState,
-    types::{Type, WrongType},
-    CopyInSink, GenericClient, NoTls,
+    types::{IsNull, ToSql, Type, WrongType},
+    CopyInSink, GenericClient,
 };
 
-use super::{metricssendqueue::MetricsReceiveQueue, sink_error::SinkError};
+use super::{group_metrics, metricssendqueue::MetricsReceiveQueue, sink_error::SinkError};
 
-lazy_static! {
-    // column "available_messages" of relation "table_name" does not exist
-    static ref UNDEFINED_COLUMN: Regex = Regex::new(r#"column "(?P<column>.+)" of relation "(?P<table>.+)" does not exist"#).expect("regex compiles");
-    static ref UNDEFINED_TABLE: Regex = Regex::new(r#"relation "(?P<table>.+)" does not exist"#).expect("regex compiles");
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TypeConflictPolicy {
+    /// Alter int4, int8 and float4 columns in place to a type that holds both
+    Widen,
+    /// Rename the old column to <column>_<oldtype> and add a new one
+    Rename,
+    /// Write the conflicting rows to <table>_quarantine
+    Quarantine,
+}
+
+enum TypeConflictResolution {
+    Retry,
+    Quarantine,
+    Drop,
 }
 
 #[derive(Debug, Clone)]
 struct PostgresConfig {
-    pub default_retention: Duration,
-    pub compress_new_tables: bool,
+    pub table_policies: TablePolicies,
+    pub reconcile_table_policies: bool,
+    pub continuous_aggregates: Vec<Duration>,
+    pub continuous_aggregate_retention: Duration,
+    pub type_conflict_policies: Vec<TypeConflictPolicy>,
+    pub schema: Option<String>,
+    pub table_prefix: String,
+    /// Set when each tenant gets its own schema
+    pub tenant_dimension: Option<String>,
+}
+
+impl PostgresConfig {
+    /// Splits a metric's datums by the schema they go to. The tenant dimension picks the
+    /// schema rather than becoming a column. Datums for a tenant that can't be a schema are dropped.
+    fn by_schema(&self, datums: Vec<Datum>) -> BTreeMap<Option<String>, Vec<Datum>> {
+        let mut schemas: BTreeMap<Option<String>, Vec<Datum>> = BTreeMap::new();
+        for mut datum in datums {
+            let tenant = self
+                .tenant_dimension
+                .as_ref()
+                .and_then(|tenant_dimension| datum.dimensions.remove(tenant_dimension))
+                .and_then(|dimension| match dimension.value {
+                    Some(dimension::Value::String(tenant)) if !tenant.is_empty() => Some(tenant),
+                    _ => None,
+                });
+            if let Some(tenant) = &tenant {
+                if !ddl::is_tenant_schema(tenant) {
+                    log::warn!(
+                        "dropping {} for tenant {tenant:?}, which can't be a schema",
+                        datum.metric
+                    );
+                    continue;
+                }
+            }
+            schemas
+                .entry(tenant.or_else(|| self.schema.clone()))
+                .or_default()
+                .push(datum);
+        }
+        schemas
+    }
 }
 
 pub struct PostgresSender {
@@ -64,9 +117,55 @@ impl PostgresSender {
         options: Options,
     ) -> Result<PostgresSender, SinkError> {
         log::debug!("new_connection: {:?}", connection_string);
+        if options.postgres_tenant_schemas {
+            // Without api key tenants, clients would pick their own schema.
+            if options.api_key_tenant.is_empty() {
+                return Err(SinkError::StringError(StringError {
+                    message: "--postgres-tenant-schemas needs --api-key-tenant".to_string(),
+                }));
+            }
+            if let Some(rule) = options
+                .api_key_tenant
+                .iter()
+                .find(|rule| !ddl::is_tenant_schema(&rule.tenant))
+            {
+                return Err(SinkError::StringError(StringError {
+                    message: format!("tenant {} can't be a postgres schema", rule.tenant),
+                }));
+            }
+        }
         let max_conns = 16;
+        let tls = PostgresTls {
+            ssl_mode: options.postgres_ssl_mode,
+            root_certificate_pem: match &options.postgres_ssl_root_certificate {
+                Some(path) => Some(tokio::fs::read(path).await.map_err(|e| {
+                    SinkError::other("could not read postgres root certificate", Box::new(e))
+                })?),
+                None => None,
+            },
+            client_certificate: match (
+                &options.postgres_ssl_client_certificate,
+                &options.postgres_ssl_client_private_key,
+            ) {
+                (Some(certificate), Some(private_key)) => Some(ClientCertificate {
+                    certificate_pem: tokio::fs::read(certificate).await.map_err(|e| {
+                        SinkError::other("could not read postgres client certificate", Box::new(e))
+                    })?,
+                    private_key_pem: tokio::fs::read(private_key).await.map_err(|e| {
+                        SinkError::other("could not read postgres client private key", Box::new(e))
+                    })?,
+                }),
+                (None, None) => None,
+                _ => {
+                    return Err(SinkError::StringError(StringError {
+                        message: "postgres client certificate and private key go together"
+                            .to_string(),
+                    }))
+                }
+            },
+        };
         let mut connector =
-            PostgresConnector::new(connection_string.to_string(), max_conns).await?;
+            PostgresConnector::new(connection_string.to_string(), max_conns, tls).await?;
 
         let type_converter = {
             let statistic_set_type = get_or_create_statistic_set_type(&mut connector).await?;
@@ -84,8 +183,18 @@ impl PostgresSender {
             rx,
             type_converter,
             configuration: PostgresConfig {
-                default_retention: options.default_retention,
-                compress_new_tables: options.compress_new_tables,
+                table_policies: TablePolicies::new(&options),
+                reconcile_table_policies: options.reconcile_table_policies,
+                continuous_aggregates: options.continuous_aggregate,
+                continuous_aggregate_retention: options.continuous_aggregate_retention,
+                type_conflict_policies: options.postgres_type_conflict,
+                schema: options.postgres_schema.filter(|schema| !schema.is_empty()),
+                table_prefix: options.postgres_table_prefix,
+                tenant_dimension: if options.postgres_tenant_schemas {
+                    Some(options.tenant_dimension)
+                } else {
+                    None
+                },
             },
         })
     }
@@ -94,6 +203,7 @@ impl PostgresSender {
         log::info!("started postgres consumer");
         let connector = Rc::new(self.connector);
         let type_converter = Rc::new(self.type_converter);
+        let table_schemas = Rc::new(TableSchemas::default());
 
         while let Some(mut batch) = self.rx.recv().await {
             log::info!("Sender woke. Trying to collect a batch...");
@@ -109,6 +219,7 @@ impl PostgresSender {
 
             let batch_connector = connector.clone();
             let batch_type_converter = type_converter.clone();
+            let batch_table_schemas = table_schemas.clone();
             let batch_configuration = self.configuration.clone();
             batch_tasks
                 .run_until(async move {
@@ -122,13 +233,17 @@ impl PostgresSender {
                     );
 
                     for (metric, datums) in grouped_metrics.into_iter() {
-                        task::spawn_local(PostgresSender::send_some(
-                            batch_configuration.clone(),
-                            batch_connector.clone(),
-                            batch_type_converter.clone(),
-                            metric,
-                            datums,
-                        ));
+                        for (schema, datums) in batch_configuration.by_schema(datums) {
+                            task::spawn_local(PostgresSender::send_some(
+                                batch_configuration.clone(),
+                                batch_connector.clone(),
+                                batch_type_converter.clone(),
+                                batch_table_schemas.clone(),
+                                schema,
+                                metric.clone(),
+                                datums,
+                            ));
+                        }
                     }
                 })
                 .await;
@@ -143,40 +258,110 @@ impl PostgresSender {
         configuration: PostgresConfig,
         connector: Rc<PostgresConnector>,
         type_converter: Rc<TypeConverter>,
+        table_schemas: Rc<TableSchemas>,
+        schema: Option<String>,
         metric: String,
         datums: Vec<Datum>,
     ) -> Result<(), SinkError> {
-        let mut try_again = true;
-        while try_again {
-            let connection = match connector.use_connection().await {
-                Ok(connection) => connection,
-                Err(error) => {
-                    log::error!(
-                        "Dropping metrics because I can't get a connection: {:?}",
-                        error
-                    );
-                    continue;
-                }
-            };
-            try_again =
-                match PostgresSender::run_a_batch(&connection, &type_converter, &metric, &datums)
-                    .await
+        // Quarantined rows become their own batch for the quarantine table.
+        let mut batches = vec![(metric, datums)];
+        while let Some((metric, mut datums)) = batches.pop() {
+            let mut try_again = true;
+            let mut reloaded_schema = false;
+            while try_again {
+                let connection = match connector.use_connection().await {
+                    Ok(connection) => connection,
+                    Err(error) => {
+                        log::error!(
+                            "Dropping metrics because I can't get a connection: {:?}",
+                            error
+                        );
+                        continue;
+                    }
+                };
+                try_again = match PostgresSender::run_a_batch(
+                    &connection,
+                    &configuration,
+                    &type_converter,
+                    &table_schemas,
+                    schema.as_deref(),
+                    &metric,
+                    &datums,
+                )
+                .await
                 {
                     Ok(rows) => {
                         log::info!("committed rows: {rows}", rows = rows);
 
                         false
                     }
-                    Err(e) => {
+                    Err(SinkError::TypeConflict(conflict)) => {
                         drop(connection);
                         let connection = connector.use_connection().await?;
-                        match PostgresSender::handle_error_and_should_it_retry(
+                        match PostgresSender::resolve_type_conflict(
                             &configuration,
                             &connection,
-                            e,
+                            &table_schemas,
+                            &metric,
+                            &conflict,
                         )
                         .await
                         {
+                            Ok(TypeConflictResolution::Retry) => true,
+                            Ok(TypeConflictResolution::Quarantine) => {
+                                let (quarantined, rest): (Vec<Datum>, Vec<Datum>) =
+                                    datums.into_iter().partition(|datum| {
+                                        has_column_type(&type_converter, datum, &conflict)
+                                    });
+                                if quarantined.is_empty() {
+                                    log::error!(
+                                        "Dropping batch with no rows to quarantine for {:?}",
+                                        conflict
+                                    );
+                                    break;
+                                }
+                                log::warn!(
+                                    "quarantining {} rows for {:?}",
+                                    quarantined.len(),
+                                    conflict
+                                );
+                                batches.push((format!("{metric}_quarantine"), quarantined));
+                                datums = rest;
+                                !datums.is_empty()
+                            }
+                            Ok(TypeConflictResolution::Drop) => {
+                                log::error!("Dropping batch with a type conflict: {:?}", conflict);
+
+                                false
+                            }
+                            Err(resolve_failure) => {
+                                log::error!(
+                                    "failed to resolve type conflict {:?}: {:?}",
+                                    conflict,
+                                    resolve_failure
+                                );
+
+                                false
+                            }
+                        }
+                    }
+                    Err(SinkError::StaleSchema(stale)) => {
+                        // Another writer can change the table between looking and copying, so look once more.
+                        // Failing again after that means the table isn't what goodmetrics expects at all.
+                        if reloaded_schema {
+                            log::error!(
+                                "Dropping batch, schema still stale after reloading: {stale:?}"
+                            );
+                            false
+                        } else {
+                            log::info!("table changed underneath goodmetrics, retrying: {stale:?}");
+                            reloaded_schema = true;
+                            true
+                        }
+                    }
+                    Err(e) => {
+                        drop(connection);
+                        match PostgresSender::handle_error_and_should_it_retry(e) {
                             Ok(should_retry) => should_retry,
                             Err(retry_failure) => {
                                 log::error!("failed to handle error: {:?}", retry_failure);
@@ -186,114 +371,298 @@ impl PostgresSender {
                         }
                     }
                 }
+            }
         }
         Ok(())
     }
 
+    async fn resolve_type_conflict(
+        configuration: &PostgresConfig,
+        connection: &PooledConnection<'_, ConnectionManager>,
+        table_schemas: &TableSchemas,
+        metric: &str,
+        conflict: &TypeConflict,
+    ) -> Result<TypeConflictResolution, SinkError> {
+        // The hypertable's time column is never altered; its rows can only be quarantined.
+        let is_time = conflict.column == "time";
+        for policy in &configuration.type_conflict_policies {
+            match policy {
+                TypeConflictPolicy::Widen if !is_time => {
+                    if let Some(wider) =
+                        wider_number_type(&conflict.existing_type, &conflict.new_type)
+                    {
+                        log::info!("widening {:?} to {wider}", conflict);
+                        let widened = ddl::alter_column_type(
+                            connection.client(),
+                            &conflict.table,
+                            &conflict.column,
+                            wider,
+                        )
+                        .await;
+                        table_schemas.forget(&conflict.table);
+                        match widened {
+                            Ok(()) => return Ok(TypeConflictResolution::Retry),
+                            // Compressed hypertables and continuous aggregates can refuse it.
+                            Err(e) => log::warn!("could not widen {:?}: {e:?}", conflict),
+                        }
+                    }
+                }
+                TypeConflictPolicy::Rename if !is_time => {
+                    let columns = table_schemas
+                        .columns(connection.client(), &conflict.table)
+                        .await?;
+                    let renamed = rename_target(
+                        &columns,
+                        &conflict.column,
+                        &format!("_{}", conflict.existing_type),
+                    );
+                    log::info!("renaming {:?} to {renamed}", conflict);
+                    let moved = ddl::rename_column(
+                        connection.client(),
+                        &conflict.table,
+                        &conflict.column,
+                        &renamed,
+                    )
+                    .await;
+                    table_schemas.forget(&conflict.table);
+                    match moved {
+                        Ok(()) => return Ok(TypeConflictResolution::Retry),
+                        Err(e) => log::warn!("could not rename {:?}: {e:?}", conflict),
+                    }
+                }
+                TypeConflictPolicy::Widen | TypeConflictPolicy::Rename => {}
+                TypeConflictPolicy::Quarantine => {
+                    // A conflict inside the quarantine table has nowhere further to go.
+                    if !metric.ends_with("_quarantine") {
+                        return Ok(TypeConflictResolution::Quarantine);
+                    }
+                }
+            }
+        }
+        Ok(TypeConflictResolution::Drop)
+    }
+
     async fn run_a_batch(
-        client: &PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+        client: &PooledConnection<'_, ConnectionManager>,
+        configuration: &PostgresConfig,
         type_converter: &TypeConverter,
+        table_schemas: &TableSchemas,
+        schema: Option<&str>,
         metric: &str,
         datums: &[Datum],
     ) -> Result<usize, SinkError> {
         let mut rows = 0;
 
-        let dimension_types = type_converter.get_dimension_type_map(datums);
-        let measurement_types = type_converter.get_measurement_type_map(datums);
+        let mut dimension_types = type_converter.get_dimension_type_map(datums);
+        let mut measurement_types = type_converter.get_measurement_type_map(datums);
+        widen_within_batch(type_converter, datums, &mut measurement_types);
+        text_for_large_numbers(datums, &mut dimension_types);
+        // Names that clean up to the same column would name it twice in the copy. The first one wins.
+        let mut columns_in_batch = BTreeSet::from(["time".to_string()]);
+        for column_types in [&mut dimension_types, &mut measurement_types] {
+            column_types.retain(|name, _| {
+                let unique = columns_in_batch.insert(postgres_id(name));
+                if !unique {
+                    log::warn!("dropping {name} from {metric}: its column is already in the batch");
+                }
+                unique
+            });
+        }
+
+        let table = ddl::table_name(schema, &configuration.table_prefix, metric);
+        let existing_columns = table_schemas.columns(client.client(), &table).await?;
+
+        // Numbers that fit in an existing column are written as the column's type. Anything else is a conflict.
+        let mut missing_columns: BTreeMap<String, String> = BTreeMap::new();
+        for column_types in [&mut dimension_types, &mut measurement_types] {
+            for (name, data_type) in column_types.iter_mut() {
+                let column = postgres_id(name);
+                let new_type = column_type_name(data_type);
+                let existing_type = match existing_columns.get(&column) {
+                    Some(existing_type) => existing_type,
+                    None => {
+                        missing_columns.insert(column, new_type.to_string());
+                        continue;
+                    }
+                };
+                if existing_type == new_type {
+                    continue;
+                }
+                match number_type(existing_type) {
+                    Some(existing_number)
+                        if wider_number_type(existing_type, new_type)
+                            == Some(existing_type.as_str()) =>
+                    {
+                        *data_type = existing_number;
+                    }
+                    _ => {
+                        return Err(SinkError::TypeConflict(TypeConflict {
+                            table,
+                            column,
+                            existing_type: existing_type.clone(),
+                            new_type: new_type.to_string(),
+                        }))
+                    }
+                }
+            }
+        }
+
+        let policy = configuration.table_policies.for_metric(metric);
+        // A table always has a time column, so no columns means no table.
+        let create = existing_columns.is_empty();
+        let schema_changed = create || !missing_columns.is_empty();
+        if schema_changed {
+            let mut statements = Vec::with_capacity(missing_columns.len() + 2);
+            if create {
+                log::info!("creating table {table} with {policy:?}");
+                statements.extend(ddl::create_schema(&table));
+                statements.push(ddl::create_table(&table, &policy));
+            }
+            for (column, data_type) in &missing_columns {
+                log::info!("adding column {table}.{column} {data_type}");
+                statements.push(ddl::add_column(&table, column, data_type));
+            }
+            if create {
+                statements.push(ddl::table_settings(&table, &policy, &missing_columns));
+            }
+            let applied = ddl::in_transaction(client.client(), &statements).await;
+            // Either way the cached columns are out of date now.
+            table_schemas.forget(&table);
+            if let Err(e) = applied {
+                return Err(schema_error(&table, e));
+            }
+        }
+
+        if !configuration.continuous_aggregates.is_empty()
+            && (schema_changed || !table_schemas.has_continuous_aggregates(&table))
+        {
+            let mut columns = existing_columns.clone();
+            columns.extend(missing_columns.clone());
+            let built = PostgresSender::continuous_aggregates(
+                client.client(),
+                configuration,
+                &table,
+                &policy,
+                columns,
+                &dimension_types,
+                &measurement_types,
+            )
+            .await;
+            table_schemas.continuous_aggregates_built(&table, built);
+        }
+
+        if !create
+            && configuration.reconcile_table_policies
+            && table_schemas.first_reconcile(&table)
+        {
+            log::info!("reconciling {table} with {policy:?}");
+            for statement in ddl::reconcile_table_policy(&table, &policy, &existing_columns) {
+                if let Err(e) = client.batch_execute(&statement).await {
+                    log::warn!("could not reconcile {table} with `{statement}`: {e:?}");
+                }
+            }
+        }
 
         let all_column_names = get_all_column_names(&dimension_types, &measurement_types);
+        // The timescale toolkit's tdigest has no binary input function, so those tables still get csv.
+        let binary = !measurement_types
+            .values()
+            .any(|t| *t == type_converter.tdigest_type);
 
         let sink: CopyInSink<bytes::Bytes> = match client
             .copy_in(&format!(
-                "copy {table_name} ({all_columns}) from stdin with (format csv, header false)", // with binary",
-                table_name = clean_id(metric),
+                "copy {table} ({all_columns}) from stdin with (format {format})",
                 all_columns = all_column_names.join(","),
+                format = if binary {
+                    "binary"
+                } else {
+                    "csv, header false"
+                },
             ))
             .await
         {
             Ok(sink) => sink,
-            Err(postgres_error) => match postgres_error.as_db_error() {
-                Some(dberror) => match *dberror.code() {
-                    SqlState::UNDEFINED_COLUMN => {
-                        let (table, column) = match UNDEFINED_COLUMN.captures(dberror.message()) {
-                            Some(pair) => {
-                                let table =
-                                    pair.name("table").map(|m| m.as_str()).unwrap_or_default();
-                                let column =
-                                    pair.name("column").map(|m| m.as_str()).unwrap_or_default();
-                                log::info!(
-                                    "missing column: {table}.{column}",
-                                    table = table,
-                                    column = column
-                                );
-                                (table, column)
-                            }
-                            None => {
-                                return Err(SinkError::StringError(StringError {
-                                    message: format!(
-                                        "unable to find table and column in error: {dberror:?}"
-                                    ),
-                                }));
-                            }
-                        };
-                        let the_type = datums
-                            .iter()
-                            .filter_map(|d| match d.dimensions.get(column) {
-                                Some(dim) => Some(sql_dimension_type_string(dim)),
-                                None => match d.measurements.get(column) {
-                                    Some(measurement) => Some(sql_data_type_string(measurement)),
-                                    None => None,
-                                },
-                            })
-                            .next();
-                        match the_type {
-                            Some(t) => {
-                                return Err(SinkError::MissingColumn(MissingColumn {
-                                    table: table.to_string(),
-                                    column: column.to_string(),
-                                    data_type: t.to_string(),
-                                }))
-                            }
-                            None => {
-                                return Err(SinkError::DescribedError(DescribedError {
-                                    message: "Type not foud, can't add column".to_string(),
-                                    inner: postgres_error,
-                                }))
-                            }
-                        }
-                    }
-                    SqlState::UNDEFINED_TABLE => {
-                        let table = match UNDEFINED_TABLE.captures(dberror.message()) {
-                            Some(table_captures) => table_captures
-                                .name("table")
-                                .map(|f| f.as_str())
-                                .unwrap_or_default(),
-                            None => "__unknown__",
-                        };
-                        log::info!("missing table: {table}", table = table);
-
-                        return Err(SinkError::MissingTable(MissingTable {
-                            table: table.to_string(),
-                        }));
-                    }
-                    _ => {
-                        return Err(SinkError::Postgres(postgres_error));
-                    }
-                },
-                None => return Err(SinkError::Postgres(postgres_error)),
-            },
+            Err(postgres_error) => {
+                // Someone else changed the table, so look again on the next try.
+                table_schemas.forget(&table);
+                return Err(schema_error(&table, postgres_error));
+            }
         };
 
-        rows += write_and_close(sink, &dimension_types, &measurement_types, datums).await?;
+        let written = if binary {
+            write_binary_and_close(sink, &dimension_types, &measurement_types, datums).await
+        } else {
+            write_csv_and_close(sink, &dimension_types, &measurement_types, datums).await
+        };
+        rows += match written {
+            Ok(written) => written,
+            Err(e) => {
+                table_schemas.forget(&table);
+                return Err(e);
+            }
+        };
 
         Ok(rows)
     }
 
-    async fn handle_error_and_should_it_retry(
+    /// Builds the missing continuous aggregates and replaces the ones whose columns changed.
+    async fn continuous_aggregates(
+        client: &tokio_postgres::Client,
         configuration: &PostgresConfig,
-        connection: &PooledConnection<'_, PostgresConnectionManager<NoTls>>,
-        e: SinkError,
-    ) -> Result<bool, SinkError> {
+        table: &str,
+        policy: &TablePolicy,
+        mut columns: BTreeMap<String, String>,
+        dimension_types: &BTreeMap<String, Type>,
+        measurement_types: &BTreeMap<String, Type>,
+    ) -> bool {
+        // An existing view says which columns it grouped by, which a batch can't for columns it doesn't have.
+        let mut view_columns = BTreeMap::new();
+        for bucket in &configuration.continuous_aggregates {
+            view_columns = ddl::column_types(client, &continuous_aggregate_name(table, bucket))
+                .await
+                .unwrap_or_default();
+            if !view_columns.is_empty() {
+                break;
+            }
+        }
+
+        columns.remove("time");
+        let batch_dimensions: BTreeSet<String> =
+            dimension_types.keys().map(|d| postgres_id(d)).collect();
+        // Dimensions keep their type in the view, while measurements become rollups.
+        let (dimensions, rollups): (Vec<_>, Vec<_>) =
+            columns
+                .into_iter()
+                .partition(|(column, data_type)| match view_columns.get(column) {
+                    Some(view_type) => {
+                        view_type == data_type
+                            && matches!(data_type.as_str(), "text" | "bool" | "int8")
+                    }
+                    None => {
+                        batch_dimensions.contains(column)
+                            || (!measurement_types.keys().any(|m| postgres_id(m) == *column)
+                                && matches!(data_type.as_str(), "text" | "bool"))
+                    }
+                });
+        build_continuous_aggregates(
+            client,
+            table,
+            &configuration.continuous_aggregates,
+            &policy.retention,
+            &configuration.continuous_aggregate_retention,
+            &dimensions
+                .into_iter()
+                .map(|(column, _)| column)
+                .collect::<Vec<_>>(),
+            &rollups
+                .into_iter()
+                .map(|(column, data_type)| Rollup { column, data_type })
+                .collect::<Vec<_>>(),
+        )
+        .await
+    }
+
+    fn handle_error_and_should_it_retry(e: SinkError) -> Result<bool, SinkError> {
         return match e {
             SinkError::Postgres(postgres_error) => match postgres_error.as_db_error() {
                 Some(dberror) => match *dberror.code() {
@@ -328,38 +697,9 @@ impl PostgresSender {
                     }
                 },
             },
-            SinkError::MissingColumn(what_column) => {
-                log::info!("adding missing column {:?}", what_column);
-                match connection.client().simple_query("select 1").await {
-                    Ok(_) => {
-                        log::info!("using connection for dml")
-                    }
-                    Err(e) => {
-                        log::info!("connection is hosed: {:?}", e)
-                    }
-                }
-
-                ddl::add_column(
-                    connection.client(),
-                    &what_column.table,
-                    &what_column.column,
-                    &what_column.data_type,
-                )
-                .await?;
-
-                Ok(true)
-            }
-            SinkError::MissingTable(what_table) => {
-                log::info!("adding missing table {:?}", what_table);
-                ddl::create_table(
-                    connection.client(),
-                    &what_table.table,
-                    &configuration.default_retention,
-                    configuration.compress_new_tables,
-                )
-                .await?;
-
-                Ok(true)
+            SinkError::StaleSchema(e) => {
+                log::error!("error while sending metrics, dropping: {e:?}");
+                Ok(false)
             }
             SinkError::DescribedError(e) => {
                 log::error!("error while sending metrics, dropping: {e:?}");
@@ -373,11 +713,162 @@ impl PostgresSender {
                 log::error!("error while sending metrics, dropping: {e:?}");
                 Ok(false)
             }
+            SinkError::TypeConflict(e) => {
+                log::error!("unresolved type conflict, dropping: {e:?}");
+                Ok(false)
+            }
+            SinkError::MissingColumn(e) => {
+                log::error!("error while sending metrics, dropping: {e:?}");
+                Ok(false)
+            }
+            SinkError::MissingTable(e) => {
+                log::error!("error while sending metrics, dropping: {e:?}");
+                Ok(false)
+            }
         };
     }
 }
 
-async fn write_and_close(
+async fn write_binary_and_close(
+    sink: CopyInSink<bytes::Bytes>,
+    dimensions: &BTreeMap<String, Type>,
+    measurements: &BTreeMap<String, Type>,
+    data: &[Datum],
+) -> Result<usize, SinkError> {
+    log::debug!("writing {} binary rows", data.len());
+
+    let types: Vec<Type> = std::iter::once(Type::TIMESTAMPTZ)
+        .chain(dimensions.values().cloned())
+        .chain(measurements.values().cloned())
+        .collect();
+    let mut writer = pin!(BinaryCopyInWriter::new(sink, &types));
+    let mut row: Vec<CopyValue> = Vec::with_capacity(types.len());
+    for datum in data {
+        row.clear();
+        row.push(CopyValue::Time(
+            SystemTime::UNIX_EPOCH + Duration::from_nanos(datum.unix_nanos),
+        ));
+        row.extend(dimensions.iter().map(|(dimension_name, data_type)| {
+            let value = match datum
+                .dimensions
+                .get(dimension_name)
+                .and_then(|d| d.value.as_ref())
+            {
+                Some(dimension::Value::String(s)) => CopyValue::Text(s),
+                Some(dimension::Value::Number(n)) => match i64::try_from(*n) {
+                    Ok(n) => CopyValue::Int8(n),
+                    Err(_) => CopyValue::OwnedText(n.to_string()),
+                },
+                Some(dimension::Value::Boolean(b)) => CopyValue::Bool(*b),
+                None => CopyValue::Null,
+            };
+            value.coerce(data_type)
+        }));
+        row.extend(measurements.iter().map(|(measurement_name, data_type)| {
+            let value = match datum
+                .measurements
+                .get(measurement_name)
+                .and_then(|m| m.value.as_ref())
+            {
+                Some(measurement::Value::I64(i)) => CopyValue::Int8(*i),
+                Some(measurement::Value::I32(i)) => CopyValue::Int4(*i),
+                Some(measurement::Value::F64(f)) => CopyValue::Float8(*f),
+                Some(measurement::Value::F32(f)) => CopyValue::Float4(*f),
+                Some(measurement::Value::StatisticSet(s)) => {
+                    CopyValue::StatisticSet(s.clone().into())
+                }
+                Some(measurement::Value::Histogram(h)) => CopyValue::Json(to_jsonmap(h)),
+                // Only reachable if a tdigest slipped past the csv check; the copy will reject it.
+                Some(measurement::Value::Tdigest(_)) | None => CopyValue::Null,
+            };
+            value.coerce(data_type)
+        }));
+        let values: Vec<&(dyn ToSql + Sync)> =
+            row.iter().map(|v| v as &(dyn ToSql + Sync)).collect();
+        writer.as_mut().write(&values).await?;
+    }
+    writer.finish().await?;
+    Ok(data.len())
+}
+
+/// One binary copy field, so rows don't need a box per value.
+#[derive(Debug)]
+enum CopyValue<'a> {
+    Time(SystemTime),
+    Text(&'a str),
+    OwnedText(String),
+    Int8(i64),
+    Int4(i32),
+    Float8(f64),
+    Float4(f32),
+    Bool(bool),
+    StatisticSet(SqlStatisticSet),
+    Json(serde_json::Value),
+    Null,
+}
+
+impl CopyValue<'_> {
+    /// Numbers going into a wider existing column, or a dimension's text column
+    fn coerce(self, data_type: &Type) -> Self {
+        match self {
+            CopyValue::Int8(i) if *data_type == Type::TEXT => CopyValue::OwnedText(i.to_string()),
+            CopyValue::Int4(i) if *data_type == Type::INT8 => CopyValue::Int8(i as i64),
+            CopyValue::Int4(i) if *data_type == Type::FLOAT8 => CopyValue::Float8(i as f64),
+            CopyValue::Int8(i) if *data_type == Type::FLOAT8 => CopyValue::Float8(i as f64),
+            CopyValue::Float4(f) if *data_type == Type::FLOAT8 => CopyValue::Float8(f as f64),
+            value => value,
+        }
+    }
+}
+
+impl ToSql for CopyValue<'_> {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut bytes::BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        match self {
+            CopyValue::Time(v) => v.to_sql(ty, out),
+            CopyValue::Text(v) => v.to_sql(ty, out),
+            CopyValue::OwnedText(v) => v.to_sql(ty, out),
+            CopyValue::Int8(v) => v.to_sql(ty, out),
+            CopyValue::Int4(v) => v.to_sql(ty, out),
+            CopyValue::Float8(v) => v.to_sql(ty, out),
+            CopyValue::Float4(v) => v.to_sql(ty, out),
+            CopyValue::Bool(v) => v.to_sql(ty, out),
+            CopyValue::StatisticSet(v) => v.to_sql(ty, out),
+            CopyValue::Json(v) => v.to_sql(ty, out),
+            CopyValue::Null => Ok(IsNull::Yes),
+        }
+    }
+
+    // Each variant checks its own type in to_sql_checked
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+
+    fn to_sql_checked(
+        &self,
+        ty: &Type,
+        out: &mut bytes::BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        match self {
+            CopyValue::Time(v) => v.to_sql_checked(ty, out),
+            CopyValue::Text(v) => v.to_sql_checked(ty, out),
+            CopyValue::OwnedText(v) => v.to_sql_checked(ty, out),
+            CopyValue::Int8(v) => v.to_sql_checked(ty, out),
+            CopyValue::Int4(v) => v.to_sql_checked(ty, out),
+            CopyValue::Float8(v) => v.to_sql_checked(ty, out),
+            CopyValue::Float4(v) => v.to_sql_checked(ty, out),
+            CopyValue::Bool(v) => v.to_sql_checked(ty, out),
+            CopyValue::StatisticSet(v) => v.to_sql_checked(ty, out),
+            CopyValue::Json(v) => v.to_sql_checked(ty, out),
+            CopyValue::Null => Ok(IsNull::Yes),
+        }
+    }
+}
+
+async fn write_csv_and_close(
     sink: CopyInSink<bytes::Bytes>,
     dimensions: &BTreeMap<String, Type>,
     measurements: &BTreeMap<String, Type>,
@@ -468,45 +959,211 @@ fn get_all_column_names(
     measurement_types: &BTreeMap<String, Type>,
 ) -> Vec<String> {
     let mut all_column_types: Vec<String> = vec!["time".to_string()];
-    all_column_types.extend(dimension_types.keys().map(|d| clean_id(d)));
-    all_column_types.extend(measurement_types.keys().map(|d| clean_id(d)));
+    all_column_types.extend(dimension_types.keys().map(|d| postgres_id(d)));
+    all_column_types.extend(measurement_types.keys().map(|d| postgres_id(d)));
     all_column_types
 }
 
-fn group_metrics(batch: Vec<Datum>) -> BTreeMap<String, Vec<Datum>> {
-    let grouped_metrics: BTreeMap<String, Vec<Datum>> = batch
-        .into_iter()
-        // TODO: fix string copying here
-        .sorted_by_key(|d| d.metric.clone())
-        .group_by(|d| d.metric.clone())
-        .into_iter()
-        .map(|(metric, datums_iterable)| (metric, datums_iterable.collect::<Vec<Datum>>()))
-        .collect();
-    grouped_metrics
+/// Like information_schema names it, with the histogram domain instead of jsonb
+fn column_type_name(data_type: &Type) -> &str {
+    if *data_type == Type::JSONB {
+        "histogram"
+    } else {
+        data_type.name()
+    }
+}
+
+fn number_type(type_name: &str) -> Option<Type> {
+    match type_name {
+        "int4" => Some(Type::INT4),
+        "int8" => Some(Type::INT8),
+        "float4" => Some(Type::FLOAT4),
+        "float8" => Some(Type::FLOAT8),
+        _ => None,
+    }
+}
+
+/// The narrowest of int4 < int8 < float8 and float4 < float8 that holds both, when both are numbers.
+fn wider_number_type(a: &str, b: &str) -> Option<&'static str> {
+    let rank = |type_name: &str| match type_name {
+        "int4" => Some(0),
+        "int8" => Some(1),
+        "float4" => Some(2),
+        "float8" => Some(3),
+        _ => None,
+    };
+    Some(match (rank(a)?, rank(b)?) {
+        (0, 0) => "int4",
+        (0 | 1, 0 | 1) => "int8",
+        (2, 2) => "float4",
+        _ => "float8",
+    })
+}
+
+/// <column>_<oldtype>, or with a number after it when a type flipped back and that's taken
+fn rename_target(columns: &BTreeMap<String, String>, column: &str, suffix: &str) -> String {
+    let renamed = ddl::with_suffix(column, suffix);
+    if !columns.contains_key(&renamed) {
+        return renamed;
+    }
+    (2..)
+        .map(|n| ddl::with_suffix(column, &format!("{suffix}_{n}")))
+        .find(|candidate| !columns.contains_key(candidate))
+        .expect("some number is free")
+}
+
+/// The type map takes whichever type came last. A measurement that is more than 1 kind of number in
+/// a batch gets the type that holds them all, and each value is coerced into it.
+fn widen_within_batch(
+    type_converter: &TypeConverter,
+    datums: &[Datum],
+    measurement_types: &mut BTreeMap<String, Type>,
+) {
+    for datum in datums {
+        for (name, measurement) in &datum.measurements {
+            let batch_type = match measurement_types.get_mut(name) {
+                Some(batch_type) => batch_type,
+                None => continue,
+            };
+            let wider = type_converter
+                .measurement_sql_type(measurement)
+                .and_then(|data_type| wider_number_type(data_type.name(), batch_type.name()))
+                .and_then(number_type);
+            if let Some(wider) = wider {
+                *batch_type = wider;
+            }
+        }
+    }
+}
+
+/// int8 can't hold the top half of u64. Those dimensions go in as text, so they conflict with an
+/// int8 column like any other type change instead of wrapping around to negative numbers.
+fn dimension_type(type_converter: &TypeConverter, dimension: &Dimension) -> Option<Type> {
+    match dimension.value {
+        Some(dimension::Value::Number(n)) if i64::try_from(n).is_err() => Some(Type::TEXT),
+        _ => type_converter.dimension_sql_type(dimension),
+    }
 }
 
-fn sql_data_type_string(measurement: &Measurement) -> &'static str {
-    match &measurement.value {
-        Some(value) => match value {
-            measurement::Value::I64(_) => "int8",
-            measurement::Value::I32(_) => "int4",
-            measurement::Value::F64(_) => "float8",
-            measurement::Value::F32(_) => "float4",
-            measurement::Value::StatisticSet(_) => "statistic_set",
-            measurement::Value::Histogram(_) => "histogram",
-            measurement::Value::Tdigest(_) => "tdigest",
-        },
-        None => "unsupported",
+/// The type map takes whichever type came last, so a number too large for int8 anywhere in the
+/// batch makes the whole column text.
+fn text_for_large_numbers(datums: &[Datum], dimension_types: &mut BTreeMap<String, Type>) {
+    for datum in datums {
+        for (name, dimension) in &datum.dimensions {
+            if let Some(dimension::Value::Number(n)) = dimension.value {
+                if i64::try_from(n).is_err() {
+                    dimension_types.insert(name.clone(), Type::TEXT);
+                }
+            }
+        }
+    }
+}
+
+/// Whether the datum is one of the rows that brought the conflicting type
+fn has_column_type(type_converter: &TypeConverter, datum: &Datum, conflict: &TypeConflict) -> bool {
+    let dimension_types = datum.dimensions.iter().filter_map(|(name, dimension)| {
+        dimension_type(type_converter, dimension).map(|t| (name, t))
+    });
+    let measurement_types = datum.measurements.iter().filter_map(|(name, measurement)| {
+        type_converter
+            .measurement_sql_type(measurement)
+            .map(|t| (name, t))
+    });
+    // A batch's numbers are widened together, so any number may have brought a number conflict.
+    let is_number_conflict = number_type(&conflict.new_type).is_some();
+    dimension_types.chain(measurement_types).any(|(name, t)| {
+        postgres_id(name) == conflict.column
+            && (column_type_name(&t) == conflict.new_type
+                || (is_number_conflict && number_type(column_type_name(&t)).is_some()))
+    })
+}
+
+/// Errors that mean the cached columns were wrong, which another try after reloading them can fix
+fn schema_error(table: &str, e: tokio_postgres::Error) -> SinkError {
+    match e.as_db_error().map(|dberror| dberror.code().clone()) {
+        Some(
+            SqlState::UNDEFINED_TABLE
+            | SqlState::UNDEFINED_COLUMN
+            | SqlState::DUPLICATE_TABLE
+            | SqlState::DUPLICATE_COLUMN,
+        ) => SinkError::StaleSchema(StaleSchema {
+            table: table.to_string(),
+        }),
+        _ => SinkError::Postgres(e),
     }
 }
 
-fn sql_dimension_type_string(dimension: &Dimension) -> &'static str {
-    match &dimension.value {
-        Some(value) => match value {
-            dimension::Value::String(_) => "text",
-            dimension::Value::Number(_) => "int8",
-            dimension::Value::Boolean(_) => "boolean",
-        },
-        None => "unsupported",
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{rename_target, wider_number_type};
+
+    fn columns(names: &[&str]) -> BTreeMap<String, String> {
+        names
+            .iter()
+            .map(|name| (name.to_string(), "int8".to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn same_number_types_stay() {
+        for t in ["int4", "int8", "float4", "float8"] {
+            assert_eq!(wider_number_type(t, t), Some(t));
+        }
+    }
+
+    #[test]
+    fn numbers_widen_in_order() {
+        assert_eq!(wider_number_type("int4", "int8"), Some("int8"));
+        assert_eq!(wider_number_type("int8", "int4"), Some("int8"));
+        assert_eq!(wider_number_type("float4", "float8"), Some("float8"));
+        assert_eq!(wider_number_type("int8", "float8"), Some("float8"));
+    }
+
+    #[test]
+    fn integers_and_float4_need_float8() {
+        // float4 can't hold every int4, let alone every int8
+        assert_eq!(wider_number_type("int4", "float4"), Some("float8"));
+        assert_eq!(wider_number_type("float4", "int8"), Some("float8"));
+    }
+
+    #[test]
+    fn other_types_do_not_widen() {
+        assert_eq!(wider_number_type("text", "int8"), None);
+        assert_eq!(wider_number_type("int4", "bool"), None);
+        assert_eq!(wider_number_type("statistic_set", "float8"), None);
+    }
+
+    #[test]
+    fn rename_uses_the_old_type() {
+        assert_eq!(rename_target(&columns(&["x"]), "x", "_int8"), "x_int8");
+    }
+
+    #[test]
+    fn rename_skips_taken_names() {
+        assert_eq!(
+            rename_target(&columns(&["x", "x_int8"]), "x", "_int8"),
+            "x_int8_2"
+        );
+        assert_eq!(
+            rename_target(&columns(&["x", "x_int8", "x_int8_2"]), "x", "_int8"),
+            "x_int8_3"
+        );
+    }
+
+    #[test]
+    fn rename_fits_the_identifier_limit() {
+        let column = "c".repeat(63);
+        let taken = format!("{}_int8", "c".repeat(58));
+
+        let renamed = rename_target(
+            &columns(&[column.as_str(), taken.as_str()]),
+            &column,
+            "_int8",
+        );
+
+        assert_eq!(renamed, format!("{}_int8_2", "c".repeat(56)));
+        assert!(renamed.len() <= 63);
     }
 }
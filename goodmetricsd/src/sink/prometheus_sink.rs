@@ -0,0 +1,337 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    convert::Infallible,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use communication::proto::goodmetrics::{dimension, measurement, Datum};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+use itertools::Itertools;
+use tokio::time::Instant;
+
+use crate::config::options::Options;
+
+use super::{
+    metricssendqueue::MetricsReceiveQueue,
+    sink_error::{SinkError, StringError},
+};
+
+#[derive(Debug, Clone)]
+enum Value {
+    Gauge(f64),
+    /// Upper bound -> count, like goodmetrics histograms.
+    Histogram(BTreeMap<i64, u64>),
+    Summary {
+        sum: f64,
+        count: u64,
+        min: f64,
+        max: f64,
+    },
+}
+
+#[derive(Debug)]
+struct Sample {
+    value: Value,
+    unix_nanos: u64,
+    updated: Instant,
+}
+
+/// family name -> rendered labels -> latest sample
+type Families = BTreeMap<String, BTreeMap<String, Sample>>;
+
+/// Keeps the latest value of each metric, measurement and dimension set, and serves them
+/// on `/metrics` for Prometheus to scrape. Series that stop reporting expire after the staleness window.
+pub struct PrometheusExporter {
+    rx: MetricsReceiveQueue,
+    listen_address: SocketAddr,
+    staleness: Duration,
+    families: Arc<Mutex<Families>>,
+}
+
+impl PrometheusExporter {
+    pub fn new(rx: MetricsReceiveQueue, options: Options) -> Result<PrometheusExporter, SinkError> {
+        let listen_address = options
+            .prometheus_listen_address
+            .unwrap_or_default()
+            .parse()
+            .map_err(|e| {
+                SinkError::StringError(StringError {
+                    message: format!("prometheus listen address is not valid: {e:?}"),
+                })
+            })?;
+
+        Ok(PrometheusExporter {
+            rx,
+            listen_address,
+            staleness: options.prometheus_staleness,
+            families: Default::default(),
+        })
+    }
+
+    pub async fn consume_stuff(mut self) -> Result<u32, SinkError> {
+        log::info!(
+            "started prometheus consumer, serving on {}",
+            self.listen_address
+        );
+
+        let server_families = self.families.clone();
+        let staleness = self.staleness;
+        let make_service = make_service_fn(move |_connection| {
+            let families = server_families.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let families = families.clone();
+                    async move { Ok::<_, Infallible>(respond(&families, staleness, request)) }
+                }))
+            }
+        });
+        let server = hyper::Server::try_bind(&self.listen_address)
+            .map_err(|e| SinkError::other("could not bind prometheus listener", Box::new(e)))?
+            .serve(make_service);
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                log::error!("prometheus server stopped: {:?}", e);
+            }
+        });
+
+        while let Some(batch) = self.rx.recv().await {
+            log::debug!("recording {} datums for prometheus", batch.len());
+            let mut families = self.families.lock().expect("lock is not poisoned");
+            for datum in batch {
+                record(&mut families, datum);
+            }
+            expire(&mut families, self.staleness);
+        }
+
+        log::info!("ended prometheus consumer");
+        Ok(1)
+    }
+}
+
+fn record(families: &mut Families, datum: Datum) {
+    let labels = series_labels(&datum)
+        .into_iter()
+        .map(|(name, value)| format!("{name}=\"{}\"", escape_label_value(&value)))
+        .join(",");
+
+    for (name, measurement) in datum.measurements {
+        let value = match measurement.value {
+            Some(value) => match value {
+                measurement::Value::I64(i) => Value::Gauge(i as f64),
+                measurement::Value::I32(i) => Value::Gauge(i as f64),
+                measurement::Value::F64(f) => Value::Gauge(f),
+                measurement::Value::F32(f) => Value::Gauge(f as f64),
+                measurement::Value::StatisticSet(s) => Value::Summary {
+                    sum: s.samplesum,
+                    count: s.samplecount,
+                    min: s.minimum,
+                    max: s.maximum,
+                },
+                measurement::Value::Histogram(h) => {
+                    Value::Histogram(h.buckets.into_iter().collect())
+                }
+                measurement::Value::Tdigest(t) => Value::Summary {
+                    sum: t.sum,
+                    count: t.count,
+                    min: t.min,
+                    max: t.max,
+                },
+            },
+            None => continue,
+        };
+        // Same namespace splaying as the opentelemetry sink.
+        let family = sanitize_name(&format!("{}_{}", datum.metric, name));
+        let series = families.entry(family).or_default();
+        match series.get(&labels) {
+            Some(existing) if datum.unix_nanos < existing.unix_nanos => continue,
+            _ => {
+                series.insert(
+                    labels.clone(),
+                    Sample {
+                        value,
+                        unix_nanos: datum.unix_nanos,
+                        updated: Instant::now(),
+                    },
+                );
+            }
+        }
+    }
+}
+
+fn expire(families: &mut Families, staleness: Duration) {
+    families.retain(|_, series| {
+        series.retain(|_, sample| sample.updated.elapsed() < staleness);
+        !series.is_empty()
+    });
+}
+
+fn respond(
+    families: &Mutex<Families>,
+    staleness: Duration,
+    request: Request<Body>,
+) -> Response<Body> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("try /metrics"))
+            .expect("response can be built");
+    }
+    let body = {
+        let mut families = families.lock().expect("lock is not poisoned");
+        expire(&mut families, staleness);
+        render(&families)
+    };
+    Response::builder()
+        .header("content-type", "text/plain; version=0.0.4")
+        .body(Body::from(body))
+        .expect("response can be built")
+}
+
+fn render(families: &Families) -> String {
+    let mut out = String::new();
+    for (family, series) in families {
+        // A family only gets 1 type; the first sample decides and mismatches are skipped.
+        let kind = match series.values().next().map(|s| &s.value) {
+            Some(Value::Gauge(_)) => "gauge",
+            Some(Value::Histogram(_)) => "histogram",
+            Some(Value::Summary { .. }) => "summary",
+            None => continue,
+        };
+        let _ = writeln!(out, "# TYPE {family} {kind}");
+        let mut mins = String::new();
+        let mut maxes = String::new();
+        for (labels, sample) in series {
+            match (&sample.value, kind) {
+                (Value::Gauge(v), "gauge") => {
+                    let _ = writeln!(out, "{family}{} {}", braces(labels), float(*v));
+                }
+                (Value::Histogram(buckets), "histogram") => {
+                    let mut cumulative = 0;
+                    for (bucket, count) in buckets {
+                        cumulative += count;
+                        let _ = writeln!(
+                            out,
+                            "{family}_bucket{} {cumulative}",
+                            braces(&join_labels(labels, &format!("le=\"{bucket}\"")))
+                        );
+                    }
+                    let _ = writeln!(
+                        out,
+                        "{family}_bucket{} {cumulative}",
+                        braces(&join_labels(labels, "le=\"+Inf\""))
+                    );
+                    let _ = writeln!(out, "{family}_count{} {cumulative}", braces(labels));
+                }
+                (
+                    Value::Summary {
+                        sum,
+                        count,
+                        min,
+                        max,
+                    },
+                    "summary",
+                ) => {
+                    let _ = writeln!(out, "{family}_sum{} {}", braces(labels), float(*sum));
+                    let _ = writeln!(out, "{family}_count{} {count}", braces(labels));
+                    let _ = writeln!(mins, "{family}_min{} {}", braces(labels), float(*min));
+                    let _ = writeln!(maxes, "{family}_max{} {}", braces(labels), float(*max));
+                }
+                _ => log::debug!("skipping {family} series with a different type"),
+            }
+        }
+        if !mins.is_empty() {
+            // min and max are not part of a prometheus summary, so they get their own families.
+            let _ = write!(out, "# TYPE {family}_min gauge\n{mins}");
+            let _ = write!(out, "# TYPE {family}_max gauge\n{maxes}");
+        }
+    }
+    out
+}
+
+fn braces(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{labels}}}")
+    }
+}
+
+fn join_labels(labels: &str, extra: &str) -> String {
+    if labels.is_empty() {
+        extra.to_string()
+    } else {
+        format!("{labels},{extra}")
+    }
+}
+
+fn float(f: f64) -> String {
+    if f.is_nan() {
+        "NaN".to_string()
+    } else if f == f64::INFINITY {
+        "+Inf".to_string()
+    } else if f == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        f.to_string()
+    }
+}
+
+/// Dimensions as labels. Names prometheus reserves, like `le` and `__name__`, become `exported_<name>`,
+/// and when 2 dimensions sanitize to the same name only the first, by name, is kept.
+pub fn series_labels(datum: &Datum) -> BTreeMap<String, String> {
+    let mut labels = BTreeMap::new();
+    for (name, dimension) in datum.dimensions.iter().sorted_by_key(|(name, _)| *name) {
+        let value = match &dimension.value {
+            Some(dimension::Value::String(s)) => s.clone(),
+            Some(dimension::Value::Number(n)) => n.to_string(),
+            Some(dimension::Value::Boolean(b)) => b.to_string(),
+            None => continue,
+        };
+        let mut label = sanitize_name(name);
+        if label == "le" || label.starts_with("__") {
+            label = format!("exported_{label}");
+        }
+        match labels.entry(label) {
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+            }
+            Entry::Occupied(entry) => {
+                log::debug!(
+                    "dropping dimension {name} that clashes with label {}",
+                    entry.key()
+                );
+            }
+        }
+    }
+    labels
+}
+
+pub fn sanitize_name(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
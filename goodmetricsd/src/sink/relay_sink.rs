@@ -0,0 +1,203 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use communication::proto::goodmetrics::{metrics_client::MetricsClient, Datum, MetricsRequest};
+use communication::{get_channel, ChannelType};
+use tokio::sync::Semaphore;
+use tokio::time::{sleep, timeout_at, Instant};
+use tonic::metadata::AsciiMetadataValue;
+use tonic::Code;
+
+use crate::config::options::Options;
+use crate::ingest::sampling::fnv1a;
+
+use super::sink_error::StringError;
+use super::{metricssendqueue::MetricsReceiveQueue, sink_error::SinkError};
+
+#[derive(Debug, Clone)]
+struct RelayConfig {
+    pub batch_window: Duration,
+    pub max_batch_size: usize,
+    pub max_retries: u32,
+    pub authorization: Option<AsciiMetadataValue>,
+}
+
+/// Shards being sent or retried at once. Past this, the consumer waits before reading more.
+const MAX_IN_FLIGHT_SHARDS: usize = 64;
+
+#[derive(Clone)]
+struct Upstream {
+    endpoint: String,
+    client: MetricsClient<ChannelType>,
+}
+
+/// Forwards batches to upstream goodmetricsd instances. Each metric is always sent to the
+/// same upstream (rendezvous hashing on the metric name), so an upstream's tables only
+/// see the metrics it owns and losing an upstream only moves that upstream's metrics.
+pub struct RelaySender {
+    rx: MetricsReceiveQueue,
+    upstreams: Vec<Upstream>,
+    configuration: RelayConfig,
+}
+
+impl RelaySender {
+    pub async fn new_connection(
+        rx: MetricsReceiveQueue,
+        options: Options,
+    ) -> Result<RelaySender, SinkError> {
+        let mut upstreams = Vec::with_capacity(options.relay_upstream.len());
+        for endpoint in &options.relay_upstream {
+            let client = match get_channel(endpoint, options.relay_insecure).await {
+                Ok(channel) => MetricsClient::new(channel),
+                Err(e) => {
+                    return Err(SinkError::StringError(StringError {
+                        message: format!("Could not get a relay channel to {endpoint}: {e:?}"),
+                    }))
+                }
+            };
+            upstreams.push(Upstream {
+                endpoint: endpoint.clone(),
+                client,
+            });
+        }
+        let authorization = match &options.relay_authorization {
+            Some(token) => Some(AsciiMetadataValue::try_from(token.as_str()).map_err(|e| {
+                SinkError::StringError(StringError {
+                    message: format!("relay authorization token is not well-formed: {e:?}"),
+                })
+            })?),
+            None => None,
+        };
+
+        Ok(RelaySender {
+            rx,
+            upstreams,
+            configuration: RelayConfig {
+                batch_window: options.relay_batch_window,
+                max_batch_size: options.relay_max_batch_size.max(1),
+                max_retries: options.relay_max_retries,
+                authorization,
+            },
+        })
+    }
+
+    pub async fn consume_stuff(mut self) -> Result<u32, SinkError> {
+        log::info!(
+            "started relay consumer for {} upstreams",
+            self.upstreams.len()
+        );
+        if self.upstreams.is_empty() {
+            return Err(SinkError::StringError(StringError {
+                message: "relay needs at least 1 upstream".to_string(),
+            }));
+        }
+
+        // Retries happen off the receive path, so a slow upstream doesn't stall reading the queue.
+        let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT_SHARDS));
+        while let Some(mut batch) = self.rx.recv().await {
+            log::info!("Sender woke. Trying to collect a batch...");
+
+            let deadline = Instant::now() + self.configuration.batch_window;
+            let mut api_calls: u32 = 1;
+            while let Ok(Some(mut extras)) = timeout_at(deadline, self.rx.recv()).await {
+                api_calls += 1;
+                batch.append(&mut extras);
+            }
+            let batchlen = batch.len();
+
+            let mut shards: BTreeMap<usize, Vec<Datum>> = BTreeMap::new();
+            for datum in batch {
+                let upstream = pick_upstream(&self.upstreams, &datum.metric);
+                shards.entry(upstream).or_default().push(datum);
+            }
+            log::info!(
+                "Relaying some metrics. batch size: {}, upstreams: {}, api calls: {}",
+                batchlen,
+                shards.len(),
+                api_calls,
+            );
+
+            for (i, datums) in shards {
+                let permit = match in_flight.clone().acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => break,
+                };
+                let configuration = self.configuration.clone();
+                let upstream = self.upstreams[i].clone();
+                tokio::spawn(async move {
+                    send_shard(&configuration, upstream, datums).await;
+                    drop(permit);
+                });
+            }
+        }
+
+        log::info!("ended relay consumer");
+        Ok(1)
+    }
+}
+
+async fn send_shard(configuration: &RelayConfig, mut upstream: Upstream, datums: Vec<Datum>) {
+    for chunk in datums.chunks(configuration.max_batch_size) {
+        let mut attempt = 0;
+        loop {
+            let mut request = tonic::Request::new(MetricsRequest {
+                shared_dimensions: HashMap::new(),
+                metrics: chunk.to_vec(),
+            });
+            if let Some(token) = &configuration.authorization {
+                request
+                    .metadata_mut()
+                    .insert("authorization", token.clone());
+            }
+            match upstream.client.send_metrics(request).await {
+                Ok(_) => {
+                    log::debug!("relayed {} datums to {}", chunk.len(), upstream.endpoint);
+                    break;
+                }
+                Err(status) => {
+                    if attempt < configuration.max_retries && is_retryable(status.code()) {
+                        let backoff = Duration::from_millis(100 * (1 << attempt.min(6)));
+                        log::warn!(
+                            "relay to {} failed, retrying in {:?}: {:?}",
+                            upstream.endpoint,
+                            backoff,
+                            status
+                        );
+                        attempt += 1;
+                        sleep(backoff).await;
+                    } else {
+                        log::error!(
+                            "dropping {} datums for {}: {:?}",
+                            chunk.len(),
+                            upstream.endpoint,
+                            status
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn is_retryable(code: Code) -> bool {
+    matches!(
+        code,
+        Code::Unavailable
+            | Code::ResourceExhausted
+            | Code::DeadlineExceeded
+            | Code::Aborted
+            | Code::Internal
+            | Code::Unknown
+    )
+}
+
+fn pick_upstream(upstreams: &[Upstream], metric: &str) -> usize {
+    upstreams
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, upstream)| fnv1a(format!("{}/{metric}", upstream.endpoint).as_bytes()))
+        .map(|(i, _)| i)
+        .unwrap_or_default()
+}
@@ -0,0 +1,331 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use communication::proto::{
+    goodmetrics::{measurement, Datum},
+    prometheus::{Label, Sample, TimeSeries, WriteRequest},
+};
+use prost::Message;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    StatusCode,
+};
+use tokio::time::{sleep, timeout_at, Instant};
+
+use crate::config::options::Options;
+
+use super::{
+    metricssendqueue::MetricsReceiveQueue,
+    prometheus_sink::{sanitize_name, series_labels},
+    sink_error::{SinkError, StringError},
+};
+
+/// Sorted labels, including __name__
+type SeriesKey = Vec<(String, String)>;
+
+#[derive(Debug, Clone)]
+struct RemoteWriteConfig {
+    pub batch_window: Duration,
+    pub max_samples_per_request: usize,
+    pub max_retries: u32,
+}
+
+/// Pushes datums to a prometheus remote_write endpoint, named like the opentelemetry sink names them.
+/// Requests are sent 1 at a time so each series' samples arrive in order.
+pub struct RemoteWriteSender {
+    rx: MetricsReceiveQueue,
+    client: reqwest::Client,
+    endpoint: String,
+    configuration: RemoteWriteConfig,
+    /// Newest sample sent per series. Remote write rejects older samples, so they are dropped here.
+    last_sent: HashMap<SeriesKey, (i64, Instant)>,
+    totals: Totals,
+}
+
+impl RemoteWriteSender {
+    pub fn new(rx: MetricsReceiveQueue, options: Options) -> Result<RemoteWriteSender, SinkError> {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-encoding", HeaderValue::from_static("snappy"));
+        headers.insert(
+            "content-type",
+            HeaderValue::from_static("application/x-protobuf"),
+        );
+        headers.insert(
+            "x-prometheus-remote-write-version",
+            HeaderValue::from_static("0.1.0"),
+        );
+        for header in &options.remote_write_header {
+            let (name, value) = header.split_once('=').ok_or_else(|| {
+                SinkError::StringError(StringError {
+                    message: format!("remote write header needs <name>=<value>: {header}"),
+                })
+            })?;
+            headers.insert(
+                HeaderName::try_from(name.trim())
+                    .map_err(|e| SinkError::other("bad remote write header name", Box::new(e)))?,
+                HeaderValue::try_from(value.trim())
+                    .map_err(|e| SinkError::other("bad remote write header value", Box::new(e)))?,
+            );
+        }
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(|e| SinkError::other("could not build remote write client", Box::new(e)))?;
+
+        Ok(RemoteWriteSender {
+            rx,
+            client,
+            endpoint: options.remote_write_endpoint.unwrap_or_default(),
+            configuration: RemoteWriteConfig {
+                batch_window: options.remote_write_batch_window,
+                max_samples_per_request: options.remote_write_max_samples_per_request.max(1),
+                max_retries: options.remote_write_max_retries,
+            },
+            last_sent: HashMap::new(),
+            totals: Totals::default(),
+        })
+    }
+
+    pub async fn consume_stuff(mut self) -> Result<u32, SinkError> {
+        log::info!("started remote write consumer for {}", self.endpoint);
+
+        while let Some(mut batch) = self.rx.recv().await {
+            log::info!("Sender woke. Trying to collect a batch...");
+
+            let deadline = Instant::now() + self.configuration.batch_window;
+            let mut api_calls: u32 = 1;
+            while let Ok(Some(mut extras)) = timeout_at(deadline, self.rx.recv()).await {
+                api_calls += 1;
+                batch.append(&mut extras);
+            }
+
+            // Totals add up in time order, so each series' samples only go up.
+            batch.sort_by_key(|datum| datum.unix_nanos);
+            let now = Instant::now();
+            self.totals.expire(now);
+            let mut series: BTreeMap<SeriesKey, Vec<Sample>> = BTreeMap::new();
+            for datum in batch {
+                for (key, sample) in expand_datum(datum, &mut self.totals, now) {
+                    series.entry(key).or_default().push(sample);
+                }
+            }
+            let timeseries = self.in_order(series);
+            log::info!(
+                "Sending some metrics. series: {}, api calls: {}",
+                timeseries.len(),
+                api_calls,
+            );
+
+            let mut request = Vec::new();
+            let mut request_samples = 0;
+            for one_series in timeseries {
+                request_samples += one_series.samples.len();
+                request.push(one_series);
+                if self.configuration.max_samples_per_request <= request_samples {
+                    self.send(std::mem::take(&mut request)).await;
+                    request_samples = 0;
+                }
+            }
+            if !request.is_empty() {
+                self.send(request).await;
+            }
+        }
+
+        log::info!("ended remote write consumer");
+        Ok(1)
+    }
+
+    /// Sorts each series' samples and drops the ones older than what was already sent.
+    fn in_order(&mut self, series: BTreeMap<SeriesKey, Vec<Sample>>) -> Vec<TimeSeries> {
+        let now = Instant::now();
+        self.last_sent
+            .retain(|_, (_, updated)| now.duration_since(*updated) < SERIES_EXPIRY);
+
+        series
+            .into_iter()
+            .filter_map(|(key, mut samples)| {
+                samples.sort_by_key(|s| s.timestamp);
+                let newest_sent = self.last_sent.get(&key).map(|(timestamp, _)| *timestamp);
+                if let Some(newest_sent) = newest_sent {
+                    samples.retain(|s| newest_sent < s.timestamp);
+                }
+                // The sort is stable, so the newest total for a timestamp comes last. Keep that one.
+                samples.dedup_by(|later, earlier| {
+                    if later.timestamp == earlier.timestamp {
+                        *earlier = later.clone();
+                        true
+                    } else {
+                        false
+                    }
+                });
+                let newest = samples.last()?.timestamp;
+                self.last_sent.insert(key.clone(), (newest, now));
+
+                Some(TimeSeries {
+                    labels: key
+                        .into_iter()
+                        .map(|(name, value)| Label { name, value })
+                        .collect(),
+                    samples,
+                })
+            })
+            .collect()
+    }
+
+    async fn send(&self, timeseries: Vec<TimeSeries>) {
+        let series_count = timeseries.len();
+        let body = match snap::raw::Encoder::new()
+            .compress_vec(&WriteRequest { timeseries }.encode_to_vec())
+        {
+            Ok(body) => body,
+            Err(e) => {
+                log::error!("dropping {series_count} series, can't compress: {e:?}");
+                return;
+            }
+        };
+
+        let mut attempt = 0;
+        loop {
+            let retryable = match self
+                .client
+                .post(&self.endpoint)
+                .body(body.clone())
+                .send()
+                .await
+            {
+                Ok(response) if response.status().is_success() => {
+                    log::debug!("sent {series_count} series");
+                    return;
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    log::warn!(
+                        "remote write responded {status}: {:?}",
+                        response.text().await.unwrap_or_default()
+                    );
+                    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+                }
+                Err(e) => {
+                    log::warn!("remote write failed: {e:?}");
+                    true
+                }
+            };
+            if !retryable || self.configuration.max_retries <= attempt {
+                log::error!("dropping {series_count} series after {attempt} retries");
+                return;
+            }
+            sleep(Duration::from_millis(100 * (1 << attempt.min(6)))).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Running totals per series. goodmetrics sends deltas, but prometheus reads `_bucket`, `_count`
+/// and `_sum` as counters, so they have to keep going up.
+#[derive(Default)]
+struct Totals {
+    counters: HashMap<SeriesKey, (f64, Instant)>,
+    /// Keyed by the `_bucket` series without `le`
+    histograms: HashMap<SeriesKey, (BTreeMap<i64, u64>, Instant)>,
+}
+
+impl Totals {
+    fn add(&mut self, key: &SeriesKey, delta: f64, now: Instant) -> f64 {
+        let (total, updated) = self.counters.entry(key.clone()).or_insert((0.0, now));
+        *total += delta;
+        *updated = now;
+        *total
+    }
+
+    /// Forgets series that stopped reporting. They start over from 0, which prometheus reads as a reset.
+    fn expire(&mut self, now: Instant) {
+        self.counters
+            .retain(|_, (_, updated)| now.duration_since(*updated) < SERIES_EXPIRY);
+        self.histograms
+            .retain(|_, (_, updated)| now.duration_since(*updated) < SERIES_EXPIRY);
+    }
+}
+
+const SERIES_EXPIRY: Duration = Duration::from_secs(3600);
+
+fn expand_datum(datum: Datum, totals: &mut Totals, now: Instant) -> Vec<(SeriesKey, Sample)> {
+    let timestamp = (datum.unix_nanos / 1_000_000) as i64;
+    let labels = series_labels(&datum);
+    let series = |name: String, extra: Option<(&str, String)>| -> SeriesKey {
+        let mut key: SeriesKey = labels.clone().into_iter().collect();
+        key.push(("__name__".to_string(), name));
+        if let Some((label, value)) = extra {
+            key.push((label.to_string(), value));
+        }
+        key.sort();
+        key
+    };
+    let sample = |value: f64| Sample { value, timestamp };
+
+    let mut expanded = Vec::new();
+    for (name, measurement) in datum.measurements {
+        let family = sanitize_name(&format!("{}_{}", datum.metric, name));
+        let value = match measurement.value {
+            Some(value) => value,
+            None => continue,
+        };
+        match value {
+            measurement::Value::I64(i) => expanded.push((series(family, None), sample(i as f64))),
+            measurement::Value::I32(i) => expanded.push((series(family, None), sample(i as f64))),
+            measurement::Value::F64(f) => expanded.push((series(family, None), sample(f))),
+            measurement::Value::F32(f) => expanded.push((series(family, None), sample(f as f64))),
+            measurement::Value::StatisticSet(s) => {
+                let sum = series(format!("{family}_sum"), None);
+                let total = totals.add(&sum, s.samplesum, now);
+                expanded.push((sum, sample(total)));
+                let count = series(format!("{family}_count"), None);
+                let total = totals.add(&count, s.samplecount as f64, now);
+                expanded.push((count, sample(total)));
+                expanded.push((series(format!("{family}_min"), None), sample(s.minimum)));
+                expanded.push((series(format!("{family}_max"), None), sample(s.maximum)));
+            }
+            measurement::Value::Histogram(h) => {
+                let (buckets, updated) = totals
+                    .histograms
+                    .entry(series(format!("{family}_bucket"), None))
+                    .or_insert_with(|| (BTreeMap::new(), now));
+                *updated = now;
+                for (bucket, count) in h.buckets {
+                    *buckets.entry(bucket).or_default() += count;
+                }
+                // Every bound seen so far is sent every time, so a bucket never falls behind the ones under it.
+                let mut cumulative = 0;
+                for (bucket, count) in buckets.iter() {
+                    cumulative += count;
+                    expanded.push((
+                        series(format!("{family}_bucket"), Some(("le", bucket.to_string()))),
+                        sample(cumulative as f64),
+                    ));
+                }
+                expanded.push((
+                    series(format!("{family}_bucket"), Some(("le", "+Inf".to_string()))),
+                    sample(cumulative as f64),
+                ));
+                expanded.push((
+                    series(format!("{family}_count"), None),
+                    sample(cumulative as f64),
+                ));
+                // No _sum: goodmetrics histograms don't keep one, and bucket bounds aren't values.
+            }
+            measurement::Value::Tdigest(t) => {
+                let sum = series(format!("{family}_sum"), None);
+                let total = totals.add(&sum, t.sum, now);
+                expanded.push((sum, sample(total)));
+                let count = series(format!("{family}_count"), None);
+                let total = totals.add(&count, t.count as f64, now);
+                expanded.push((count, sample(total)));
+                expanded.push((series(format!("{family}_min"), None), sample(t.min)));
+                expanded.push((series(format!("{family}_max"), None), sample(t.max)));
+            }
+        }
+    }
+    expanded
+}
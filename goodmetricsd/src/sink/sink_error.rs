@@ -19,6 +19,12 @@ pub enum SinkError {
     #[error("i gotta have more table")]
     MissingTable(#[from] MissingTable),
 
+    #[error("a column changed its type")]
+    TypeConflict(#[from] TypeConflict),
+
+    #[error("the table changed since its columns were cached")]
+    StaleSchema(#[from] StaleSchema),
+
     #[error("something else happened")]
     OtherError(#[from] OtherError),
 }
@@ -103,3 +109,35 @@ impl Display for MissingColumn {
             .finish()
     }
 }
+
+#[derive(Debug, Error)]
+pub struct TypeConflict {
+    pub table: String,
+    pub column: String,
+    pub existing_type: String,
+    pub new_type: String,
+}
+
+impl Display for TypeConflict {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.debug_struct("TypeConflict")
+            .field("table", &self.table)
+            .field("column", &self.column)
+            .field("existing_type", &self.existing_type)
+            .field("new_type", &self.new_type)
+            .finish()
+    }
+}
+
+#[derive(Debug, Error)]
+pub struct StaleSchema {
+    pub table: String,
+}
+
+impl Display for StaleSchema {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.debug_struct("StaleSchema")
+            .field("table", &self.table)
+            .finish()
+    }
+}
@@ -0,0 +1,271 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use communication::proto::goodmetrics::{dimension, measurement, Datum, Dimension, Measurement};
+use itertools::Itertools;
+use lazy_static::lazy_static;
+use regex::Regex;
+use rusqlite::{params_from_iter, types::Value, Connection};
+use tokio::time::{timeout_at, Instant};
+
+use crate::{
+    config::options::Options,
+    postgres_things::{ddl::clean_id, histogram::to_jsonmap},
+};
+
+use super::{
+    group_metrics,
+    metricssendqueue::MetricsReceiveQueue,
+    sink_error::{MissingColumn, MissingTable, SinkError},
+};
+
+lazy_static! {
+    // table some_table has no column named some_column
+    static ref UNDEFINED_COLUMN: Regex = Regex::new(r#"table (?P<table>\S+) has no column named (?P<column>\S+)"#).expect("regex compiles");
+    // no such table: some_table
+    static ref UNDEFINED_TABLE: Regex = Regex::new(r#"no such table: (?P<table>\S+)"#).expect("regex compiles");
+}
+
+/// A table per metric in a local sqlite file, healing its schema the same way the postgres sink does.
+/// statistic_set, histogram and tdigest measurements are stored as json text.
+pub struct SqliteSender {
+    rx: MetricsReceiveQueue,
+    connection: Connection,
+}
+
+impl SqliteSender {
+    pub fn new(rx: MetricsReceiveQueue, options: Options) -> Result<SqliteSender, SinkError> {
+        let path = options.sqlite_path.unwrap_or_default();
+        let connection = Connection::open(&path)
+            .map_err(|e| SinkError::other("could not open sqlite database", Box::new(e)))?;
+        // So you can poke at the database while goodmetricsd is writing to it.
+        connection
+            .pragma_update(None, "journal_mode", "WAL")
+            .map_err(|e| SinkError::other("could not set sqlite journal mode", Box::new(e)))?;
+
+        Ok(SqliteSender { rx, connection })
+    }
+
+    pub async fn consume_stuff(mut self) -> Result<u32, SinkError> {
+        log::info!("started sqlite consumer");
+
+        while let Some(mut batch) = self.rx.recv().await {
+            log::info!("Sender woke. Trying to collect a batch...");
+
+            let deadline = Instant::now() + Duration::from_secs(1);
+            while let Ok(Some(mut extras)) = timeout_at(deadline, self.rx.recv()).await {
+                batch.append(&mut extras);
+            }
+
+            for (metric, datums) in group_metrics(batch) {
+                self.send_some(&clean_id(&metric), &datums);
+            }
+        }
+
+        log::info!("ended sqlite consumer");
+        Ok(1)
+    }
+
+    fn send_some(&mut self, table: &str, datums: &[Datum]) {
+        let mut try_again = true;
+        while try_again {
+            try_again = match self.run_a_batch(table, datums) {
+                Ok(rows) => {
+                    log::info!("committed rows: {rows}");
+
+                    false
+                }
+                Err(e) => match self.handle_error_and_should_it_retry(e) {
+                    Ok(should_retry) => should_retry,
+                    Err(retry_failure) => {
+                        log::error!("failed to handle error: {:?}", retry_failure);
+
+                        false
+                    }
+                },
+            }
+        }
+    }
+
+    fn run_a_batch(&mut self, table: &str, datums: &[Datum]) -> Result<usize, SinkError> {
+        let dimension_names: Vec<&String> = datums
+            .iter()
+            .flat_map(|d| d.dimensions.keys())
+            .unique()
+            .sorted()
+            .collect();
+        let measurement_names: Vec<&String> = datums
+            .iter()
+            .flat_map(|d| d.measurements.keys())
+            .unique()
+            .sorted()
+            .collect();
+        let columns = std::iter::once("time".to_string())
+            .chain(dimension_names.iter().map(|d| clean_id(d)))
+            .chain(measurement_names.iter().map(|m| clean_id(m)))
+            .collect_vec();
+
+        let transaction = self
+            .connection
+            .transaction()
+            .map_err(|e| SinkError::other("could not start sqlite transaction", Box::new(e)))?;
+        {
+            let mut statement = match transaction.prepare(&format!(
+                "insert into {table} ({columns}) values ({placeholders})",
+                columns = columns.join(","),
+                placeholders = columns.iter().map(|_| "?").join(","),
+            )) {
+                Ok(statement) => statement,
+                Err(e) => return Err(schema_error(e, datums)),
+            };
+            for datum in datums {
+                let row = std::iter::once(Value::Text(
+                    humantime::format_rfc3339_nanos(
+                        UNIX_EPOCH + Duration::from_nanos(datum.unix_nanos),
+                    )
+                    .to_string(),
+                ))
+                .chain(
+                    dimension_names
+                        .iter()
+                        .map(|name| dimension_value(datum.dimensions.get(*name))),
+                )
+                .chain(
+                    measurement_names
+                        .iter()
+                        .map(|name| measurement_value(datum.measurements.get(*name))),
+                );
+                statement
+                    .execute(params_from_iter(row))
+                    .map_err(|e| SinkError::other("failed inserting sqlite row", Box::new(e)))?;
+            }
+        }
+        transaction
+            .commit()
+            .map_err(|e| SinkError::other("could not commit sqlite transaction", Box::new(e)))?;
+
+        Ok(datums.len())
+    }
+
+    fn handle_error_and_should_it_retry(&mut self, e: SinkError) -> Result<bool, SinkError> {
+        match e {
+            SinkError::MissingColumn(what_column) => {
+                log::info!("adding missing column {:?}", what_column);
+                self.connection
+                    .execute_batch(&format!(
+                        "alter table {table} add column {column} {data_type}",
+                        table = what_column.table,
+                        column = what_column.column,
+                        data_type = what_column.data_type,
+                    ))
+                    .map_err(|e| SinkError::other("failed to add sqlite column", Box::new(e)))?;
+
+                Ok(true)
+            }
+            SinkError::MissingTable(what_table) => {
+                log::info!("adding missing table {:?}", what_table);
+                self.connection
+                    .execute_batch(&format!(
+                        "create table {table} (time text); create index {table}_time on {table} (time);",
+                        table = what_table.table,
+                    ))
+                    .map_err(|e| SinkError::other("failed to create sqlite table", Box::new(e)))?;
+
+                Ok(true)
+            }
+            e => {
+                log::error!("error while sending metrics, dropping: {e:?}");
+                Ok(false)
+            }
+        }
+    }
+}
+
+fn schema_error(e: rusqlite::Error, datums: &[Datum]) -> SinkError {
+    let message = e.to_string();
+    if let Some(captures) = UNDEFINED_COLUMN.captures(&message) {
+        let table = captures
+            .name("table")
+            .map(|m| m.as_str())
+            .unwrap_or_default();
+        let column = captures
+            .name("column")
+            .map(|m| m.as_str())
+            .unwrap_or_default();
+        let data_type = datums
+            .iter()
+            .find_map(|d| {
+                match d
+                    .dimensions
+                    .iter()
+                    .find(|(name, _)| clean_id(name) == column)
+                {
+                    Some((_, dimension)) => sqlite_dimension_type(dimension),
+                    None => d
+                        .measurements
+                        .iter()
+                        .find(|(name, _)| clean_id(name) == column)
+                        .and_then(|(_, measurement)| sqlite_measurement_type(measurement)),
+                }
+            })
+            .unwrap_or("blob");
+        return SinkError::MissingColumn(MissingColumn {
+            table: table.to_string(),
+            column: column.to_string(),
+            data_type: data_type.to_string(),
+        });
+    }
+    if let Some(captures) = UNDEFINED_TABLE.captures(&message) {
+        return SinkError::MissingTable(MissingTable {
+            table: captures
+                .name("table")
+                .map(|m| m.as_str())
+                .unwrap_or_default()
+                .to_string(),
+        });
+    }
+    SinkError::other("failed preparing sqlite insert", Box::new(e))
+}
+
+fn sqlite_dimension_type(dimension: &Dimension) -> Option<&'static str> {
+    dimension.value.as_ref().map(|value| match value {
+        dimension::Value::String(_) => "text",
+        dimension::Value::Number(_) => "integer",
+        dimension::Value::Boolean(_) => "boolean",
+    })
+}
+
+fn sqlite_measurement_type(measurement: &Measurement) -> Option<&'static str> {
+    measurement.value.as_ref().map(|value| match value {
+        measurement::Value::I64(_) | measurement::Value::I32(_) => "integer",
+        measurement::Value::F64(_) | measurement::Value::F32(_) => "real",
+        measurement::Value::StatisticSet(_)
+        | measurement::Value::Histogram(_)
+        | measurement::Value::Tdigest(_) => "json",
+    })
+}
+
+fn dimension_value(dimension: Option<&Dimension>) -> Value {
+    match dimension.and_then(|d| d.value.as_ref()) {
+        Some(dimension::Value::String(s)) => Value::Text(s.clone()),
+        Some(dimension::Value::Number(n)) => Value::Integer(*n as i64),
+        Some(dimension::Value::Boolean(b)) => Value::Integer(*b as i64),
+        None => Value::Null,
+    }
+}
+
+fn measurement_value(measurement: Option<&Measurement>) -> Value {
+    match measurement.and_then(|m| m.value.as_ref()) {
+        Some(measurement::Value::I64(i)) => Value::Integer(*i),
+        Some(measurement::Value::I32(i)) => Value::Integer(*i as i64),
+        Some(measurement::Value::F64(f)) => Value::Real(*f),
+        Some(measurement::Value::F32(f)) => Value::Real(*f as f64),
+        Some(measurement::Value::StatisticSet(s)) => {
+            Value::Text(serde_json::to_string(s).unwrap_or_default())
+        }
+        Some(measurement::Value::Histogram(h)) => Value::Text(to_jsonmap(h).to_string()),
+        Some(measurement::Value::Tdigest(t)) => {
+            Value::Text(serde_json::to_string(t).unwrap_or_default())
+        }
+        None => Value::Null,
+    }
+}